@@ -1,28 +1,35 @@
 use ic_cdk::export::candid::CandidType;
+use ic_websocket_cdk::{send_message_from_canister, WebSocketHandler, WebsocketMessage};
 use serde::{Deserialize, Serialize};
 use serde_cbor::{from_slice, Serializer};
 
-use crate::{sock::send_message_from_canister, WebsocketMessage};
-
 #[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq)]
 #[candid_path("ic_cdk::export::candid")]
 pub struct AppMessage {
     pub text: String,
 }
 
-pub fn ws_on_open(client_id: u64) {
-    let msg = AppMessage {
-        text: String::from("ping"),
-    };
-    ws_send_app_message(client_id, msg);
-}
+pub struct AppHandler;
+
+impl WebSocketHandler for AppHandler {
+    fn on_open(&self, client_id: u64) {
+        let msg = AppMessage {
+            text: String::from("ping"),
+        };
+        ws_send_app_message(client_id, msg);
+    }
+
+    fn on_message(&self, content: WebsocketMessage) {
+        let app_msg: AppMessage = from_slice(&content.message).unwrap();
+        let new_msg = AppMessage {
+            text: app_msg.text + " ping",
+        };
+        ws_send_app_message(content.client_id, new_msg)
+    }
 
-pub fn ws_on_message(content: WebsocketMessage) {
-    let app_msg: AppMessage = from_slice(&content.message).unwrap();
-    let new_msg = AppMessage {
-        text: app_msg.text + " ping",
-    };
-    ws_send_app_message(content.client_id, new_msg)
+    fn on_close(&self, client_id: u64) {
+        ic_cdk::print(format!("Client #{} disconnected.", client_id));
+    }
 }
 
 pub fn ws_send_app_message(client_id: u64, msg: AppMessage) {
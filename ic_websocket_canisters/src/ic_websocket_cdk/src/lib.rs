@@ -0,0 +1,205 @@
+use ed25519_compact::{PublicKey, Signature};
+use ic_cdk::api::caller;
+use ic_cdk_macros::*;
+use ic_websocket_protocol::{decode, CertMessages, ClientMessage, FirstMessage};
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use sock::get_cert_messages;
+use sock::get_client_gateway;
+use sock::get_client_incoming_num;
+use sock::get_client_public_key;
+use sock::put_client_incoming_num;
+use sock::{
+    delete_acknowledged_messages, delete_client, delete_expired_gateways, delete_expired_messages,
+    get_expired_clients, next_client_id, put_client_activity, put_client_caller,
+    put_client_gateway, put_client_public_key, put_gateway_activity,
+};
+
+pub use ic_websocket_protocol::{WebsocketMessage, WsError};
+pub use sock::{
+    publish, restore_state, save_state, send_message_from_canister, subscribe, unsubscribe,
+    StableState,
+};
+
+mod sock;
+
+const CLIENT_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const MESSAGE_CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Callbacks through which the websocket endpoints notify the application canister.
+pub trait WebSocketHandler {
+    // Called after the gateway opened the websocket for client_id.
+    fn on_open(&self, client_id: u64);
+    // Called for every client message that passed signature and sequence number checks.
+    fn on_message(&self, content: WebsocketMessage);
+    // Called before the state of client_id is removed, either because the gateway closed
+    // the websocket or because the client expired after a period of inactivity.
+    fn on_close(&self, client_id: u64);
+}
+
+thread_local! {
+    static HANDLER: RefCell<Option<Rc<dyn WebSocketHandler>>> = const { RefCell::new(None) };
+}
+
+// Registers the application callbacks and starts the timers that expire inactive clients and old messages.
+// Neither is kept across upgrades, so this has to be called from both the init and the post_upgrade hooks of the canister.
+// The websocket state itself is kept with save_state() in pre_upgrade and restore_state() in post_upgrade.
+pub fn init(handler: impl WebSocketHandler + 'static) {
+    HANDLER.with(|h| {
+        h.replace(Some(Rc::new(handler)));
+    });
+    ic_cdk_timers::set_timer_interval(CLIENT_EXPIRY_INTERVAL, expire_clients);
+    ic_cdk_timers::set_timer_interval(MESSAGE_CLEANUP_INTERVAL, clean_up_messages);
+}
+
+fn handler() -> Option<Rc<dyn WebSocketHandler>> {
+    HANDLER.with(|h| h.borrow().clone())
+}
+
+// Client submits its public key and gets a new client_id back.
+#[update]
+fn ws_register(public_key: Vec<u8>) -> Result<u64, WsError> {
+    let client_key = PublicKey::from_slice(&public_key).map_err(|_| WsError::InvalidPublicKey)?;
+    let client_id = next_client_id();
+    // Store the client key.
+    put_client_public_key(client_id, client_key);
+    // The identity (caller) used in this update call will be associated with this client_id. Remember this identity.
+    put_client_caller(client_id);
+    put_client_activity(client_id);
+    Ok(client_id)
+}
+
+// A method for the gateway to get the client's public key and verify the signature of the first websocket message.
+#[query]
+pub fn ws_get_client_key(client_id: u64) -> Result<Vec<u8>, WsError> {
+    get_client_public_key(client_id)
+        .map(|key| key.to_vec())
+        .ok_or(WsError::UnknownClient(client_id))
+}
+
+// Verify that sig is the signature of msg by the key registered for client_id.
+fn verify_client_signature(client_id: u64, msg: &[u8], sig: &[u8]) -> Result<(), WsError> {
+    let client_key = get_client_public_key(client_id).ok_or(WsError::UnknownClient(client_id))?;
+    let sig = Signature::from_slice(sig).map_err(|_| WsError::BadSignature)?;
+    client_key
+        .verify(msg, &sig)
+        .map_err(|_| WsError::BadSignature)
+}
+
+// Open the websocket connection.
+#[update]
+fn ws_open(msg: Vec<u8>, sig: Vec<u8>) -> Result<(), WsError> {
+    let decoded: FirstMessage = decode(&msg).map_err(|e| WsError::DecodeError(e.to_string()))?;
+
+    let client_id = decoded.client_id;
+    verify_client_signature(client_id, &msg, &sig)?;
+
+    // The signed first message can be opened only once, so that another caller replaying it
+    // cannot take the client over from the gateway that opened the websocket.
+    if get_client_gateway(client_id).is_some() {
+        return Err(WsError::Unauthorized);
+    }
+
+    // Remember this gateway will get the messages for this client_id.
+    put_client_gateway(client_id);
+    put_client_activity(client_id);
+    put_gateway_activity();
+
+    if let Some(handler) = handler() {
+        handler.on_open(client_id);
+    }
+    Ok(())
+}
+
+// Only the gateway that opened the websocket for client_id may close it or relay its messages.
+fn check_client_gateway(client_id: u64) -> Result<(), WsError> {
+    match get_client_gateway(client_id) {
+        Some(gateway) if gateway == caller().to_string() => Ok(()),
+        _ => Err(WsError::Unauthorized),
+    }
+}
+
+// Close the websocket connection.
+#[update]
+fn ws_close(client_id: u64) -> Result<(), WsError> {
+    check_client_gateway(client_id)?;
+
+    close_client(client_id);
+    Ok(())
+}
+
+// Notify the application and remove the client's state.
+fn close_client(client_id: u64) {
+    if let Some(handler) = handler() {
+        handler.on_close(client_id);
+    }
+    delete_client(client_id);
+}
+
+// Remove clients that have been inactive for too long. The clients whose websocket is open
+// expire only once their gateway has been inactive for as long, i.e. it is presumably gone.
+// Clients that never opened their websocket are removed without notifying the application.
+fn expire_clients() {
+    for client_id in get_expired_clients() {
+        if get_client_gateway(client_id).is_some() {
+            close_client(client_id);
+        } else {
+            delete_client(client_id);
+        }
+    }
+    delete_expired_gateways();
+}
+
+// Delete expired outgoing messages in bounded batches.
+// A full batch schedules the next one right away instead of waiting for the next interval.
+fn clean_up_messages() {
+    if delete_expired_messages() {
+        ic_cdk_timers::set_timer(Duration::ZERO, clean_up_messages);
+    }
+}
+
+// Gateway calls this method to pass on the message from the client to the canister.
+#[update]
+fn ws_message(msg: Vec<u8>) -> Result<(), WsError> {
+    let decoded: ClientMessage = decode(&msg).map_err(|e| WsError::DecodeError(e.to_string()))?;
+    let content: WebsocketMessage =
+        decode(&decoded.val).map_err(|e| WsError::DecodeError(e.to_string()))?;
+
+    let client_id = content.client_id;
+    check_client_gateway(client_id)?;
+
+    // Verify the signature.
+    verify_client_signature(client_id, &decoded.val, &decoded.sig)?;
+
+    // Verify the message sequence number.
+    let expected = get_client_incoming_num(client_id);
+    if content.sequence_num != expected {
+        return Err(WsError::BadSequence {
+            expected,
+            received: content.sequence_num,
+        });
+    }
+    put_client_incoming_num(client_id, content.sequence_num + 1);
+    put_client_activity(client_id);
+    put_gateway_activity();
+    if let Some(handler) = handler() {
+        handler.on_message(content);
+    }
+    Ok(())
+}
+
+// Gateway polls this method to get messages for all the clients it serves.
+// Only messages queued for the calling gateway are returned.
+#[query]
+fn ws_get_messages(nonce: u64) -> CertMessages {
+    get_cert_messages(nonce)
+}
+
+// Gateway calls this method to acknowledge that it received all messages with nonces smaller than nonce.
+// The acknowledged messages are deleted right away instead of when they expire.
+// Gateways also call it periodically to keep the websockets of their clients from expiring.
+#[update]
+fn ws_ack(nonce: u64) {
+    put_gateway_activity();
+    delete_acknowledged_messages(nonce);
+}
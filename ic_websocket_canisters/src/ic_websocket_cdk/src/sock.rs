@@ -0,0 +1,836 @@
+use ed25519_compact::PublicKey;
+use ic_cdk::api::{caller, data_certificate, set_certified_data, time};
+use ic_cdk::export::candid::CandidType;
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash as ICHash, RbTree};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Serializer;
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell, collections::BTreeSet, collections::HashMap, collections::VecDeque,
+    convert::AsRef, time::Duration,
+};
+
+use ic_websocket_protocol::{
+    encode, message_key, CertMessages, EncodedMessage, WebsocketMessage, WsError,
+};
+
+const LABEL_WEBSOCKET: &[u8] = b"websocket";
+const MSG_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_NUMBER_OF_RETURNED_MESSAGES: usize = 50;
+const MAX_NUMBER_OF_DELETED_MESSAGES: usize = 1000;
+
+#[derive(CandidType, Clone, Deserialize, PartialEq, Eq, Debug)]
+#[candid_path("ic_cdk::export::candid")]
+pub struct KeyGatewayTime {
+    key: String,
+    gateway: String,
+    time: u64,
+}
+
+thread_local! {
+    static NEXT_CLIENT_ID: RefCell<u64> = const { RefCell::new(16u64) };
+    static CLIENT_CALLER_MAP: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new());
+    static CLIENT_PUBLIC_KEY_MAP: RefCell<HashMap<u64, PublicKey>> = RefCell::new(HashMap::new());
+    static CLIENT_GATEWAY_MAP: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new());
+    static CLIENT_MESSAGE_NUM_MAP: RefCell<HashMap<u64, u64>> = RefCell::new(HashMap::new());
+    static CLIENT_INCOMING_NUM_MAP: RefCell<HashMap<u64, u64>> = RefCell::new(HashMap::new());
+    static CLIENT_ACTIVITY_MAP: RefCell<HashMap<u64, u64>> = RefCell::new(HashMap::new());
+    static GATEWAY_ACTIVITY_MAP: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static TOPIC_SUBSCRIBERS_MAP: RefCell<HashMap<String, BTreeSet<u64>>> = RefCell::new(HashMap::new());
+    static TOPIC_MESSAGE_NUM_MAP: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static GATEWAY_MESSAGES_MAP: RefCell<HashMap<String, VecDeque<EncodedMessage>>> = RefCell::new(HashMap::new());
+    static MESSAGE_DELETE_QUEUE: RefCell<VecDeque<KeyGatewayTime>> = const { RefCell::new(VecDeque::new()) };
+    static CERT_TREE: RefCell<RbTree<String, ICHash>> = const { RefCell::new(RbTree::new()) };
+    static NEXT_MESSAGE_NONCE: RefCell<u64> = const { RefCell::new(16u64) };
+}
+
+// Snapshot of the websocket state, saved to stable memory by the canister across upgrades.
+// The certified tree is not part of it: it is rebuilt from the queued messages.
+// Fields added after the first version are optional, so that the state saved by an older version
+// of the canister still decodes.
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug)]
+#[candid_path("ic_cdk::export::candid")]
+pub struct StableState {
+    next_client_id: u64,
+    client_caller_map: HashMap<u64, String>,
+    client_public_key_map: HashMap<u64, Vec<u8>>,
+    client_gateway_map: HashMap<u64, String>,
+    client_message_num_map: HashMap<u64, u64>,
+    client_incoming_num_map: HashMap<u64, u64>,
+    client_activity_map: Option<HashMap<u64, u64>>,
+    topic_subscribers_map: Option<HashMap<String, BTreeSet<u64>>>,
+    topic_message_num_map: Option<HashMap<String, u64>>,
+    gateway_activity_map: Option<HashMap<String, u64>>,
+    gateway_messages_map: HashMap<String, VecDeque<EncodedMessage>>,
+    message_delete_queue: VecDeque<KeyGatewayTime>,
+    next_message_nonce: u64,
+}
+
+pub fn save_state() -> StableState {
+    StableState {
+        next_client_id: NEXT_CLIENT_ID.with(|next_id| *next_id.borrow()),
+        client_caller_map: CLIENT_CALLER_MAP.with(|map| map.borrow().clone()),
+        client_public_key_map: CLIENT_PUBLIC_KEY_MAP.with(|map| {
+            map.borrow()
+                .iter()
+                .map(|(client_id, key)| (*client_id, key.to_vec()))
+                .collect()
+        }),
+        client_gateway_map: CLIENT_GATEWAY_MAP.with(|map| map.borrow().clone()),
+        client_message_num_map: CLIENT_MESSAGE_NUM_MAP.with(|map| map.borrow().clone()),
+        client_incoming_num_map: CLIENT_INCOMING_NUM_MAP.with(|map| map.borrow().clone()),
+        client_activity_map: Some(CLIENT_ACTIVITY_MAP.with(|map| map.borrow().clone())),
+        topic_subscribers_map: Some(TOPIC_SUBSCRIBERS_MAP.with(|map| map.borrow().clone())),
+        topic_message_num_map: Some(TOPIC_MESSAGE_NUM_MAP.with(|map| map.borrow().clone())),
+        gateway_activity_map: Some(GATEWAY_ACTIVITY_MAP.with(|map| map.borrow().clone())),
+        gateway_messages_map: GATEWAY_MESSAGES_MAP.with(|map| map.borrow().clone()),
+        message_delete_queue: MESSAGE_DELETE_QUEUE.with(|vd| vd.borrow().clone()),
+        next_message_nonce: NEXT_MESSAGE_NONCE.with(|n| *n.borrow()),
+    }
+}
+
+// Restore the state saved by save_state(), trapping if it is invalid so that the upgrade fails.
+pub fn restore_state(state: StableState) {
+    if let Err(e) = restore(state, time()) {
+        ic_cdk::trap(&format!("Could not restore the websocket state: {}", e));
+    }
+    update_certified_data();
+}
+
+// Put the state in place and rebuild the certified tree, nothing is put in place if the state is invalid.
+// Clients and gateways saved without their activity count as active at time.
+fn restore(state: StableState, time: u64) -> Result<(), String> {
+    let client_public_key_map = state
+        .client_public_key_map
+        .iter()
+        .map(|(&client_id, key)| match PublicKey::from_slice(key) {
+            Ok(key) => Ok((client_id, key)),
+            Err(e) => Err(format!(
+                "invalid public key of client #{}: {}",
+                client_id, e
+            )),
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    NEXT_CLIENT_ID.with(|next_id| next_id.replace(state.next_client_id));
+    CLIENT_CALLER_MAP.with(|map| map.replace(state.client_caller_map));
+    let gateway_activity_map = state.gateway_activity_map.unwrap_or_else(|| {
+        state
+            .client_gateway_map
+            .values()
+            .map(|gateway| (gateway.clone(), time))
+            .collect()
+    });
+    GATEWAY_ACTIVITY_MAP.with(|map| map.replace(gateway_activity_map));
+    CLIENT_GATEWAY_MAP.with(|map| map.replace(state.client_gateway_map));
+    CLIENT_MESSAGE_NUM_MAP.with(|map| map.replace(state.client_message_num_map));
+    CLIENT_INCOMING_NUM_MAP.with(|map| map.replace(state.client_incoming_num_map));
+    let client_activity_map = state.client_activity_map.unwrap_or_else(|| {
+        client_public_key_map
+            .keys()
+            .map(|&client_id| (client_id, time))
+            .collect()
+    });
+    CLIENT_ACTIVITY_MAP.with(|map| map.replace(client_activity_map));
+    CLIENT_PUBLIC_KEY_MAP.with(|map| map.replace(client_public_key_map));
+    TOPIC_SUBSCRIBERS_MAP.with(|map| map.replace(state.topic_subscribers_map.unwrap_or_default()));
+    TOPIC_MESSAGE_NUM_MAP.with(|map| map.replace(state.topic_message_num_map.unwrap_or_default()));
+    MESSAGE_DELETE_QUEUE.with(|vd| vd.replace(state.message_delete_queue));
+    NEXT_MESSAGE_NONCE.with(|n| n.replace(state.next_message_nonce));
+
+    // Recompute the certified tree so that the queued messages still verify.
+    CERT_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        for gateway_messages in state.gateway_messages_map.values() {
+            for message in gateway_messages {
+                tree.insert(message.key.clone(), Sha256::digest(&message.val).into());
+            }
+        }
+    });
+    GATEWAY_MESSAGES_MAP.with(|map| map.replace(state.gateway_messages_map));
+    Ok(())
+}
+
+pub fn next_client_id() -> u64 {
+    NEXT_CLIENT_ID.with(|next_id| next_id.replace_with(|&mut old| old + 1))
+}
+
+fn next_message_nonce() -> u64 {
+    NEXT_MESSAGE_NONCE.with(|n| n.replace_with(|&mut old| old + 1))
+}
+
+pub fn put_client_public_key(client_id: u64, client_key: PublicKey) {
+    CLIENT_PUBLIC_KEY_MAP.with(|map| {
+        map.borrow_mut().insert(client_id, client_key);
+    })
+}
+
+pub fn get_client_public_key(client_id: u64) -> Option<PublicKey> {
+    CLIENT_PUBLIC_KEY_MAP.with(|map| map.borrow().get(&client_id).cloned())
+}
+
+pub fn put_client_caller(client_id: u64) {
+    CLIENT_CALLER_MAP.with(|map| {
+        map.borrow_mut().insert(client_id, caller().to_string());
+    })
+}
+
+pub fn put_client_gateway(client_id: u64) {
+    CLIENT_GATEWAY_MAP.with(|map| {
+        map.borrow_mut().insert(client_id, caller().to_string());
+    })
+}
+
+pub fn get_client_gateway(client_id: u64) -> Option<String> {
+    CLIENT_GATEWAY_MAP.with(|map| map.borrow().get(&client_id).cloned())
+}
+
+pub fn next_client_message_num(client_id: u64) -> u64 {
+    CLIENT_MESSAGE_NUM_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        match map.get(&client_id).cloned() {
+            None => {
+                map.insert(client_id, 0);
+                0
+            }
+            Some(num) => {
+                map.insert(client_id, num + 1);
+                num + 1
+            }
+        }
+    })
+}
+
+pub fn get_client_incoming_num(client_id: u64) -> u64 {
+    CLIENT_INCOMING_NUM_MAP.with(|map| *map.borrow().get(&client_id).unwrap_or(&0))
+}
+
+pub fn put_client_incoming_num(client_id: u64, num: u64) {
+    CLIENT_INCOMING_NUM_MAP.with(|map| {
+        map.borrow_mut().insert(client_id, num);
+    })
+}
+
+// Subscribe client_id to the messages published to topic.
+pub fn subscribe(client_id: u64, topic: &str) -> Result<(), WsError> {
+    if get_client_gateway(client_id).is_none() {
+        return Err(WsError::UnknownClient(client_id));
+    }
+    TOPIC_SUBSCRIBERS_MAP.with(|map| {
+        map.borrow_mut()
+            .entry(topic.to_string())
+            .or_default()
+            .insert(client_id);
+    });
+    Ok(())
+}
+
+pub fn unsubscribe(client_id: u64, topic: &str) {
+    TOPIC_SUBSCRIBERS_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(subscribers) = map.get_mut(topic) {
+            subscribers.remove(&client_id);
+            if subscribers.is_empty() {
+                map.remove(topic);
+            }
+        }
+    });
+}
+
+fn next_topic_message_num(topic: &str) -> u64 {
+    TOPIC_MESSAGE_NUM_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        match map.get(topic).cloned() {
+            None => {
+                map.insert(topic.to_string(), 0);
+                0
+            }
+            Some(num) => {
+                map.insert(topic.to_string(), num + 1);
+                num + 1
+            }
+        }
+    })
+}
+
+// Record that client_id registered, opened its websocket or sent a message just now.
+pub fn put_client_activity(client_id: u64) {
+    CLIENT_ACTIVITY_MAP.with(|map| {
+        map.borrow_mut().insert(client_id, time());
+    })
+}
+
+// Record that the calling gateway opened a websocket, relayed a message or acknowledged messages just now.
+pub fn put_gateway_activity() {
+    GATEWAY_ACTIVITY_MAP.with(|map| {
+        map.borrow_mut().insert(caller().to_string(), time());
+    })
+}
+
+fn is_expired(time: u64, last_activity: u64) -> bool {
+    Duration::from_nanos(time.saturating_sub(last_activity)) > CLIENT_TIMEOUT
+}
+
+// Clients without any activity for CLIENT_TIMEOUT.
+pub fn get_expired_clients() -> Vec<u64> {
+    let time = time();
+    CLIENT_ACTIVITY_MAP.with(|client_activity| {
+        CLIENT_GATEWAY_MAP.with(|client_gateway| {
+            GATEWAY_ACTIVITY_MAP.with(|gateway_activity| {
+                expired_clients(
+                    time,
+                    &client_activity.borrow(),
+                    &client_gateway.borrow(),
+                    &gateway_activity.borrow(),
+                )
+            })
+        })
+    })
+}
+
+// A client whose websocket is open may only be listening, it is active as long as its gateway is.
+fn expired_clients(
+    time: u64,
+    client_activity: &HashMap<u64, u64>,
+    client_gateway: &HashMap<u64, String>,
+    gateway_activity: &HashMap<String, u64>,
+) -> Vec<u64> {
+    client_activity
+        .iter()
+        .filter(|(client_id, &last_activity)| {
+            let last_activity = client_gateway
+                .get(client_id)
+                .and_then(|gateway| gateway_activity.get(gateway))
+                .map_or(last_activity, |&gateway_activity| {
+                    gateway_activity.max(last_activity)
+                });
+            is_expired(time, last_activity)
+        })
+        .map(|(&client_id, _)| client_id)
+        .collect()
+}
+
+// Forget the gateways without any activity for CLIENT_TIMEOUT, their clients have expired.
+pub fn delete_expired_gateways() {
+    let time = time();
+    GATEWAY_ACTIVITY_MAP.with(|map| {
+        map.borrow_mut()
+            .retain(|_, &mut last_activity| !is_expired(time, last_activity));
+    });
+}
+
+pub fn delete_client(client_id: u64) {
+    CLIENT_CALLER_MAP.with(|map| {
+        map.borrow_mut().remove(&client_id);
+    });
+    CLIENT_PUBLIC_KEY_MAP.with(|map| {
+        map.borrow_mut().remove(&client_id);
+    });
+    CLIENT_GATEWAY_MAP.with(|map| {
+        map.borrow_mut().remove(&client_id);
+    });
+    CLIENT_MESSAGE_NUM_MAP.with(|map| {
+        map.borrow_mut().remove(&client_id);
+    });
+    CLIENT_INCOMING_NUM_MAP.with(|map| {
+        map.borrow_mut().remove(&client_id);
+    });
+    CLIENT_ACTIVITY_MAP.with(|map| {
+        map.borrow_mut().remove(&client_id);
+    });
+    TOPIC_SUBSCRIBERS_MAP.with(|map| {
+        map.borrow_mut().retain(|_, subscribers| {
+            subscribers.remove(&client_id);
+            !subscribers.is_empty()
+        });
+    });
+}
+
+pub fn get_cert_messages(nonce: u64) -> CertMessages {
+    GATEWAY_MESSAGES_MAP.with(|s| {
+        let gateway = caller().to_string();
+
+        let mut s = s.borrow_mut();
+        let gateway_messages_vec = match s.get_mut(&gateway) {
+            None => {
+                s.insert(gateway.clone(), VecDeque::new());
+                s.get_mut(&gateway).unwrap()
+            }
+            Some(map) => map,
+        };
+
+        let smallest_key = message_key(&gateway, nonce);
+        let start_index = gateway_messages_vec.partition_point(|x| x.key < smallest_key);
+        let mut end_index = start_index;
+        while (end_index < gateway_messages_vec.len())
+            && (end_index < start_index + MAX_NUMBER_OF_RETURNED_MESSAGES)
+        {
+            end_index += 1;
+        }
+        let mut messages: Vec<EncodedMessage> = Vec::with_capacity(end_index - start_index);
+        for index in 0..(end_index - start_index) {
+            messages.push(
+                gateway_messages_vec
+                    .get(start_index + index)
+                    .unwrap()
+                    .clone(),
+            );
+        }
+        let remaining = Some((gateway_messages_vec.len() - end_index) as u64);
+        if end_index > start_index {
+            let first_key = messages.first().unwrap().key.clone();
+            let last_key = messages.last().unwrap().key.clone();
+            let (cert, tree) = get_cert_for_range(&first_key, &last_key);
+            CertMessages {
+                messages,
+                cert,
+                tree,
+                remaining,
+            }
+        } else {
+            CertMessages {
+                messages,
+                cert: Vec::new(),
+                tree: Vec::new(),
+                remaining,
+            }
+        }
+    })
+}
+
+fn delete_message(message_info: &KeyGatewayTime) {
+    GATEWAY_MESSAGES_MAP.with(|s| {
+        let mut s = s.borrow_mut();
+        if let Some(gateway_messages) = s.get_mut(&message_info.gateway) {
+            if gateway_messages.front().map(|m| &m.key) == Some(&message_info.key) {
+                gateway_messages.pop_front();
+            }
+            if gateway_messages.is_empty() {
+                s.remove(&message_info.gateway);
+            }
+        }
+    });
+    CERT_TREE.with(|t| {
+        t.borrow_mut().delete(message_info.key.as_ref());
+    });
+}
+
+// Delete up to MAX_NUMBER_OF_DELETED_MESSAGES messages queued more than MSG_TIMEOUT ago.
+// Returns true if the batch was full, i.e. more expired messages may remain.
+pub fn delete_expired_messages() -> bool {
+    let time = time();
+    let expired: Vec<KeyGatewayTime> = MESSAGE_DELETE_QUEUE.with(|q| {
+        let mut q = q.borrow_mut();
+        let count = expired_messages(time, &q);
+        q.drain(..count).collect()
+    });
+    for message_info in &expired {
+        delete_message(message_info);
+    }
+    if !expired.is_empty() {
+        update_certified_data();
+    }
+    expired.len() == MAX_NUMBER_OF_DELETED_MESSAGES
+}
+
+// The number of messages at the front of the queue to delete in one batch. The messages are queued
+// in the order of their time, so the expired ones are at the front.
+fn expired_messages(time: u64, queue: &VecDeque<KeyGatewayTime>) -> usize {
+    queue
+        .iter()
+        .take(MAX_NUMBER_OF_DELETED_MESSAGES)
+        .take_while(|message_info| {
+            Duration::from_nanos(time.saturating_sub(message_info.time)) > MSG_TIMEOUT
+        })
+        .count()
+}
+
+// Delete the messages of the calling gateway with nonces smaller than nonce.
+pub fn delete_acknowledged_messages(nonce: u64) {
+    let gateway = caller().to_string();
+    let smallest_key = message_key(&gateway, nonce);
+
+    let deleted_keys = GATEWAY_MESSAGES_MAP.with(|s| {
+        let mut s = s.borrow_mut();
+        let mut deleted_keys = Vec::new();
+        if let Some(gateway_messages) = s.get_mut(&gateway) {
+            while let Some(front) = gateway_messages.front() {
+                if front.key >= smallest_key {
+                    break;
+                }
+                deleted_keys.push(gateway_messages.pop_front().unwrap().key);
+            }
+            if gateway_messages.is_empty() {
+                s.remove(&gateway);
+            }
+        }
+        deleted_keys
+    });
+
+    if !deleted_keys.is_empty() {
+        // The deleted messages do not wait for their expiry anymore.
+        MESSAGE_DELETE_QUEUE.with(|q| {
+            q.borrow_mut().retain(|message_info| {
+                message_info.gateway != gateway || message_info.key >= smallest_key
+            });
+        });
+        CERT_TREE.with(|t| {
+            let mut t = t.borrow_mut();
+            for key in deleted_keys {
+                t.delete(key.as_ref());
+            }
+        });
+        update_certified_data();
+    }
+}
+
+pub fn send_message_from_canister(client_id: u64, msg: Vec<u8>) {
+    let gateway = match get_client_gateway(client_id) {
+        None => {
+            return;
+        }
+        Some(gateway) => gateway,
+    };
+
+    let time = time();
+    let input = WebsocketMessage {
+        client_id,
+        sequence_num: next_client_message_num(client_id),
+        timestamp: time,
+        message: msg,
+        topic: None,
+    };
+
+    queue_message(gateway, client_id, None, encode(&input), time);
+}
+
+// Publish msg to all subscribers of topic. Every gateway serving subscribers gets a single
+// certified message together with the list of its subscribed clients, and fans it out to them.
+pub fn publish(topic: &str, msg: Vec<u8>) {
+    let gateway_subscribers = TOPIC_SUBSCRIBERS_MAP.with(|map| {
+        CLIENT_GATEWAY_MAP.with(|client_gateway| {
+            map.borrow()
+                .get(topic)
+                .map(|subscribers| subscribers_by_gateway(subscribers, &client_gateway.borrow()))
+                .unwrap_or_default()
+        })
+    });
+    if gateway_subscribers.is_empty() {
+        return;
+    }
+
+    let time = time();
+    let input = WebsocketMessage {
+        client_id: 0,
+        sequence_num: next_topic_message_num(topic),
+        timestamp: time,
+        message: msg,
+        topic: Some(topic.to_string()),
+    };
+    let data = encode(&input);
+
+    for (gateway, subscribers) in gateway_subscribers {
+        queue_message(gateway, 0, Some(subscribers), data.clone(), time);
+    }
+}
+
+// The subscribers of a topic, grouped by the gateway their websocket is open with.
+fn subscribers_by_gateway(
+    subscribers: &BTreeSet<u64>,
+    client_gateway: &HashMap<u64, String>,
+) -> HashMap<String, Vec<u64>> {
+    let mut gateway_subscribers: HashMap<String, Vec<u64>> = HashMap::new();
+    for client_id in subscribers {
+        if let Some(gateway) = client_gateway.get(client_id) {
+            gateway_subscribers
+                .entry(gateway.clone())
+                .or_default()
+                .push(*client_id);
+        }
+    }
+    gateway_subscribers
+}
+
+// Queue an encoded message for the gateway and put its hash in the certified map.
+fn queue_message(
+    gateway: String,
+    client_id: u64,
+    subscribers: Option<Vec<u64>>,
+    data: Vec<u8>,
+    time: u64,
+) {
+    let key = message_key(&gateway, next_message_nonce());
+
+    MESSAGE_DELETE_QUEUE.with(|q| {
+        q.borrow_mut().push_back(KeyGatewayTime {
+            key: key.clone(),
+            gateway: gateway.clone(),
+            time,
+        });
+    });
+
+    put_cert_for_message(key.clone(), &data);
+    GATEWAY_MESSAGES_MAP.with(|s| {
+        let mut s = s.borrow_mut();
+        let gw_map = match s.get_mut(&gateway) {
+            None => {
+                s.insert(gateway.clone(), VecDeque::new());
+                s.get_mut(&gateway).unwrap()
+            }
+            Some(map) => map,
+        };
+        gw_map.push_back(EncodedMessage {
+            client_id,
+            key,
+            val: data,
+            subscribers,
+        });
+    });
+}
+
+fn put_cert_for_message(key: String, value: &Vec<u8>) {
+    CERT_TREE.with(|tree| {
+        tree.borrow_mut()
+            .insert(key.clone(), Sha256::digest(value).into());
+    });
+    update_certified_data();
+}
+
+fn update_certified_data() {
+    let root_hash =
+        CERT_TREE.with(|tree| labeled_hash(LABEL_WEBSOCKET, &tree.borrow().root_hash()));
+    set_certified_data(&root_hash);
+}
+
+pub fn get_cert_for_range(first: &String, last: &String) -> (Vec<u8>, Vec<u8>) {
+    CERT_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = tree.value_range(first.as_ref(), last.as_ref());
+        let tree = labeled(LABEL_WEBSOCKET, witness);
+
+        let mut data = vec![];
+        let mut serializer = Serializer::new(&mut data);
+        serializer.self_describe().unwrap();
+        tree.serialize(&mut serializer).unwrap();
+        (data_certificate().unwrap(), data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    #[test]
+    fn listening_client_stays_while_its_gateway_is_active() {
+        // Client 16 opened its websocket at 0 and only receives messages since, its gateway last
+        // acknowledged messages at 50 minutes. Client 17 registered at 0 but never opened its websocket.
+        let client_activity = HashMap::from([(16, 0), (17, 0)]);
+        let client_gateway = HashMap::from([(16, "gateway".to_string())]);
+        let gateway_activity = HashMap::from([("gateway".to_string(), 50 * MINUTE)]);
+
+        let expired = expired_clients(
+            60 * MINUTE,
+            &client_activity,
+            &client_gateway,
+            &gateway_activity,
+        );
+        assert_eq!(expired, vec![17]);
+    }
+
+    #[test]
+    fn clients_expire_with_their_gateway() {
+        let client_activity = HashMap::from([(16, 0), (17, 20 * MINUTE)]);
+        let client_gateway =
+            HashMap::from([(16, "gateway".to_string()), (17, "gateway".to_string())]);
+        let gateway_activity = HashMap::from([("gateway".to_string(), 10 * MINUTE)]);
+
+        let mut expired = expired_clients(
+            45 * MINUTE,
+            &client_activity,
+            &client_gateway,
+            &gateway_activity,
+        );
+        expired.sort();
+        // Client 17 sent a message after the gateway was last active otherwise.
+        assert_eq!(expired, vec![16]);
+    }
+
+    const GATEWAY: &str = "2vxsx-fae";
+
+    // A state as saved by save_state(), with a client subscribed to a topic and messages queued
+    // for its gateway, and a client that did not open its websocket.
+    fn saved_state() -> StableState {
+        let messages: VecDeque<EncodedMessage> = (16..18)
+            .map(|nonce| EncodedMessage {
+                client_id: 16,
+                key: message_key(GATEWAY, nonce),
+                val: vec![nonce as u8; 4],
+                subscribers: None,
+            })
+            .collect();
+        StableState {
+            next_client_id: 18,
+            client_caller_map: HashMap::from([
+                (16, "caller".to_string()),
+                (17, "caller".to_string()),
+            ]),
+            client_public_key_map: HashMap::from([(16, vec![1; 32]), (17, vec![2; 32])]),
+            client_gateway_map: HashMap::from([(16, GATEWAY.to_string())]),
+            client_message_num_map: HashMap::from([(16, 1)]),
+            client_incoming_num_map: HashMap::from([(16, 3)]),
+            client_activity_map: Some(HashMap::from([(16, 5 * MINUTE), (17, 6 * MINUTE)])),
+            topic_subscribers_map: Some(HashMap::from([(
+                "news".to_string(),
+                BTreeSet::from([16]),
+            )])),
+            topic_message_num_map: Some(HashMap::from([("news".to_string(), 4)])),
+            gateway_activity_map: Some(HashMap::from([(GATEWAY.to_string(), 7 * MINUTE)])),
+            message_delete_queue: messages
+                .iter()
+                .map(|message| KeyGatewayTime {
+                    key: message.key.clone(),
+                    gateway: GATEWAY.to_string(),
+                    time: 4 * MINUTE,
+                })
+                .collect(),
+            gateway_messages_map: HashMap::from([(GATEWAY.to_string(), messages)]),
+            next_message_nonce: 18,
+        }
+    }
+
+    // The canister keeps the state in stable memory encoded in candid across the upgrade.
+    fn through_stable_memory(state: &StableState) -> StableState {
+        candid::decode_one(&candid::encode_one(state).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn state_survives_upgrade() {
+        let state = saved_state();
+        restore(through_stable_memory(&state), 60 * MINUTE).unwrap();
+        assert_eq!(save_state(), state);
+
+        // The certified tree holds the queued messages again.
+        let mut tree: RbTree<String, ICHash> = RbTree::new();
+        for message in &state.gateway_messages_map[GATEWAY] {
+            tree.insert(message.key.clone(), Sha256::digest(&message.val).into());
+        }
+        assert_eq!(CERT_TREE.with(|t| t.borrow().root_hash()), tree.root_hash());
+    }
+
+    #[test]
+    fn state_saved_by_an_older_version() {
+        let state = StableState {
+            client_activity_map: None,
+            topic_subscribers_map: None,
+            topic_message_num_map: None,
+            gateway_activity_map: None,
+            ..saved_state()
+        };
+        restore(through_stable_memory(&state), 60 * MINUTE).unwrap();
+
+        // The clients and gateways count as active at the upgrade.
+        let restored = save_state();
+        assert_eq!(
+            restored.client_activity_map,
+            Some(HashMap::from([(16, 60 * MINUTE), (17, 60 * MINUTE)]))
+        );
+        assert_eq!(
+            restored.gateway_activity_map,
+            Some(HashMap::from([(GATEWAY.to_string(), 60 * MINUTE)]))
+        );
+        assert_eq!(restored.topic_subscribers_map, Some(HashMap::new()));
+        assert_eq!(restored.topic_message_num_map, Some(HashMap::new()));
+    }
+
+    #[test]
+    fn published_messages_are_fanned_out_per_gateway() {
+        let subscribers = BTreeSet::from([16, 17, 18, 19]);
+        // Client 19 closed its websocket.
+        let client_gateway = HashMap::from([
+            (16, "gateway-a".to_string()),
+            (17, "gateway-b".to_string()),
+            (18, "gateway-a".to_string()),
+        ]);
+        assert_eq!(
+            subscribers_by_gateway(&subscribers, &client_gateway),
+            HashMap::from([
+                ("gateway-a".to_string(), vec![16, 18]),
+                ("gateway-b".to_string(), vec![17]),
+            ])
+        );
+    }
+
+    #[test]
+    fn topics_are_numbered_separately() {
+        assert_eq!(next_topic_message_num("news"), 0);
+        assert_eq!(next_topic_message_num("news"), 1);
+        assert_eq!(next_topic_message_num("weather"), 0);
+        assert_eq!(next_topic_message_num("news"), 2);
+    }
+
+    #[test]
+    fn subscriptions_end_with_the_client() {
+        // Only clients whose websocket is open can subscribe.
+        assert!(matches!(
+            subscribe(16, "news"),
+            Err(WsError::UnknownClient(16))
+        ));
+        CLIENT_GATEWAY_MAP.with(|map| {
+            map.borrow_mut()
+                .extend([(16, GATEWAY.to_string()), (17, GATEWAY.to_string())])
+        });
+        subscribe(16, "news").unwrap();
+        subscribe(16, "weather").unwrap();
+        subscribe(17, "news").unwrap();
+        let subscribers =
+            |topic: &str| TOPIC_SUBSCRIBERS_MAP.with(|map| map.borrow().get(topic).cloned());
+
+        unsubscribe(16, "weather");
+        assert_eq!(subscribers("weather"), None);
+        delete_client(17);
+        assert_eq!(subscribers("news"), Some(BTreeSet::from([16])));
+    }
+
+    fn delete_queue(times: impl Iterator<Item = u64>) -> VecDeque<KeyGatewayTime> {
+        times
+            .enumerate()
+            .map(|(nonce, time)| KeyGatewayTime {
+                key: message_key(GATEWAY, nonce as u64),
+                gateway: GATEWAY.to_string(),
+                time,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn expired_messages_are_deleted_in_batches() {
+        // 1500 messages queued at 0, then 10 at 4 minutes.
+        let mut queue = delete_queue((0..1500).map(|_| 0).chain((0..10).map(|_| 4 * MINUTE)));
+        let time = 6 * MINUTE;
+
+        // The first batch is full, so the next one follows right away and resumes from there.
+        assert_eq!(
+            expired_messages(time, &queue),
+            MAX_NUMBER_OF_DELETED_MESSAGES
+        );
+        queue.drain(..MAX_NUMBER_OF_DELETED_MESSAGES);
+        assert_eq!(expired_messages(time, &queue), 500);
+        queue.drain(..500);
+        // The messages that have not expired are left for a later interval.
+        assert_eq!(expired_messages(time, &queue), 0);
+        assert_eq!(expired_messages(10 * MINUTE, &queue), 10);
+    }
+
+    #[test]
+    fn state_with_invalid_key_is_not_restored() {
+        let mut state = saved_state();
+        state.client_public_key_map.insert(17, vec![2; 3]);
+        let e = restore(state, 60 * MINUTE).unwrap_err();
+        assert!(e.contains("client #17"), "{}", e);
+        assert_eq!(save_state().next_client_id, 16);
+    }
+}
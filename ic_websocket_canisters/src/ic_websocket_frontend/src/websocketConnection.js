@@ -22,6 +22,7 @@ export default class websocketConnection {
   constructor(canister_id, gateway_address, network_url, local_test) {
     this.canister_id = canister_id;
     this.next_received_num = 0; // Received signed messages need to come in the correct order, with sequence numbers 0, 1, 2...
+    this.next_topic_nums = {}; // Messages published to a topic are numbered per topic, starting from the first one received.
     this.instance = new WebSocket(gateway_address); // Gateway address. Here localhost to reproduce the demo.
     this.instance.binaryType = "arraybuffer";
     this.bindEvents();
@@ -78,7 +79,13 @@ export default class websocketConnection {
     console.log("[open] Connection opened");
     // Put the public key in the canister. Get client_id from the canister.
     const publicKey = await ed.getPublicKey(this.key);
-    let client_id = Number(await ic_websocket_backend.ws_register(publicKey));
+    let res = await ic_websocket_backend.ws_register(publicKey);
+    if ("Err" in res) {
+      console.log("[error] ws_register failed:", res.Err);
+      this.instance.close();
+      return;
+    }
+    let client_id = Number(res.Ok);
     this.client_id = client_id;
     this.sequence_num = 0;
 
@@ -115,11 +122,28 @@ export default class websocketConnection {
 
     // Check the sequence number
     let received_num = websocketMsg.sequence_num;
-    if (received_num != this.next_received_num) {
-      console.log(`Received message sequence number (${received_num}) does not match next expected value (${this.next_received_num}). Message ignored.`);
-      return;
+    if (websocketMsg.topic !== undefined) {
+      let expected_num = this.next_topic_nums[websocketMsg.topic];
+      if (expected_num !== undefined && received_num < expected_num) {
+        console.log(`Received sequence number (${received_num}) on topic ${websocketMsg.topic} was already received, next expected value is ${expected_num}. Message ignored.`);
+        return;
+      }
+      // Messages published while the client was not subscribed are missed, the numbering resumes from this message.
+      if (expected_num !== undefined && received_num > expected_num) {
+        console.log(`Missed ${received_num - expected_num} messages on topic ${websocketMsg.topic} before sequence number ${received_num}.`);
+      }
+      this.next_topic_nums[websocketMsg.topic] = received_num + 1;
+    } else {
+      if (received_num < this.next_received_num) {
+        console.log(`Received message sequence number (${received_num}) was already received, next expected value is ${this.next_received_num}. Message ignored.`);
+        return;
+      }
+      // Messages that expired in the canister before they were delivered are missed, the numbering resumes from this message.
+      if (received_num > this.next_received_num) {
+        console.log(`Missed ${received_num - this.next_received_num} messages before sequence number ${received_num}.`);
+      }
+      this.next_received_num = received_num + 1;
     }
-    this.next_received_num += 1;
 
     // Inspect the timestamp
     let time = websocketMsg.timestamp;
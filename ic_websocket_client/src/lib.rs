@@ -0,0 +1,492 @@
+use candid::{Decode, Encode};
+use ed25519_compact::{KeyPair, Noise};
+use futures_util::{
+    stream::{self, SplitSink},
+    SinkExt, Stream, StreamExt,
+};
+use ic_agent::{
+    agent::http_transport::ReqwestHttpReplicaV2Transport, export::Principal, Agent, AgentError,
+};
+use ic_websocket_protocol::{
+    decode, encode, verify_cert_message, CertMessage, ClientMessage, FirstMessage,
+    FirstMessageFromClient, VerifyError, WebsocketMessage, WsError,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, protocol::frame::coding::CloseCode},
+    MaybeTlsStream, WebSocketStream,
+};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const MAINNET_URL: &str = "https://ic0.app";
+
+#[derive(Debug)]
+pub enum ClientError {
+    Agent(Box<AgentError>),
+    Candid(candid::Error),
+    // The canister refused to register the client's key.
+    Register(WsError),
+    WebSocket(Box<tungstenite::Error>),
+    Decode(serde_cbor::Error),
+    // The message is not certified by the canister.
+    Verify(VerifyError),
+    // The message is addressed to another client.
+    WrongClient(u64),
+    // The message arrived out of order.
+    BadSequence { expected: u64, received: u64 },
+    // The gateway closed the websocket, e.g. because it rejected the first message.
+    Closed { code: u16, reason: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Agent(e) => write!(f, "agent error: {}", e),
+            ClientError::Candid(e) => write!(f, "candid error: {}", e),
+            ClientError::Register(e) => write!(f, "ws_register failed: {:?}", e),
+            ClientError::WebSocket(e) => write!(f, "websocket error: {}", e),
+            ClientError::Decode(e) => write!(f, "could not decode message: {}", e),
+            ClientError::Verify(e) => write!(f, "could not verify message: {}", e),
+            ClientError::WrongClient(client_id) => {
+                write!(f, "message is addressed to client #{}", client_id)
+            }
+            ClientError::BadSequence { expected, received } => write!(
+                f,
+                "received sequence number {} instead of {}",
+                received, expected
+            ),
+            ClientError::Closed { code, reason } => {
+                write!(f, "websocket closed with code {}: {}", code, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<AgentError> for ClientError {
+    fn from(e: AgentError) -> Self {
+        ClientError::Agent(Box::new(e))
+    }
+}
+
+impl From<candid::Error> for ClientError {
+    fn from(e: candid::Error) -> Self {
+        ClientError::Candid(e)
+    }
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(e: tungstenite::Error) -> Self {
+        ClientError::WebSocket(Box::new(e))
+    }
+}
+
+impl From<serde_cbor::Error> for ClientError {
+    fn from(e: serde_cbor::Error) -> Self {
+        ClientError::Decode(e)
+    }
+}
+
+impl From<VerifyError> for ClientError {
+    fn from(e: VerifyError) -> Self {
+        ClientError::Verify(e)
+    }
+}
+
+// Sending half of a websocket connection to a canister. Messages are signed with the client's key.
+pub struct WsClient {
+    client_id: u64,
+    key_pair: KeyPair,
+    sequence_num: u64,
+    sink: SplitSink<WsStream, tungstenite::Message>,
+}
+
+impl WsClient {
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    // Encode the application message in CBOR and send it to the canister.
+    pub async fn send<T: Serialize>(&mut self, message: &T) -> Result<(), ClientError> {
+        let content = WebsocketMessage {
+            client_id: self.client_id,
+            sequence_num: self.sequence_num,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+            message: encode(message),
+            topic: None,
+        };
+        let val = encode(&content);
+        let sig = self
+            .key_pair
+            .sk
+            .sign(&val, Some(Noise::generate()))
+            .to_vec();
+
+        self.sink
+            .send(tungstenite::Message::Binary(encode(&ClientMessage {
+                val,
+                sig,
+            })))
+            .await?;
+        self.sequence_num += 1;
+        Ok(())
+    }
+
+    pub async fn close(mut self) -> Result<(), ClientError> {
+        self.sink.close().await?;
+        Ok(())
+    }
+}
+
+// Connect to the canister through the gateway.
+// The client's key is registered with an anonymous agent talking to mainnet.
+pub async fn connect(
+    gateway_url: &str,
+    canister_id: Principal,
+) -> Result<
+    (
+        WsClient,
+        impl Stream<Item = Result<WebsocketMessage, ClientError>>,
+    ),
+    ClientError,
+> {
+    let transport = ReqwestHttpReplicaV2Transport::create(MAINNET_URL)?;
+    let agent = Agent::builder().with_transport(transport).build()?;
+    connect_with_agent(&agent, gateway_url, canister_id).await
+}
+
+// Connect to the canister through the gateway, registering the client's key with the given agent.
+// The returned stream yields the messages from the canister, after checking that they are
+// certified by the canister, addressed to this client and arrive in order. A gap in the messages,
+// e.g. after subscribing to a topic again, is reported as BadSequence and followed by the message
+// after it. Messages repeated or older than the last one are refused with BadSequence.
+// The certificates are verified with the root key of the agent, so an agent talking to a local
+// replica must have fetched it.
+pub async fn connect_with_agent(
+    agent: &Agent,
+    gateway_url: &str,
+    canister_id: Principal,
+) -> Result<
+    (
+        WsClient,
+        impl Stream<Item = Result<WebsocketMessage, ClientError>>,
+    ),
+    ClientError,
+> {
+    // Generate a new key for this websocket connection and register it with the canister.
+    let key_pair = KeyPair::generate();
+    let client_id = ws_register(agent, &canister_id, key_pair.pk.to_vec()).await?;
+
+    let root_key = agent.read_root_key()?;
+
+    let (ws, _) = connect_async(gateway_url).await?;
+    let (mut sink, stream) = ws.split();
+
+    // Send the first message with client and canister id, signed so that the gateway can verify they match.
+    let client_canister_id = encode(&FirstMessage {
+        client_id,
+        canister_id: canister_id.to_text(),
+    });
+    let sig = key_pair
+        .sk
+        .sign(&client_canister_id, Some(Noise::generate()))
+        .to_vec();
+    sink.send(tungstenite::Message::Binary(encode(
+        &FirstMessageFromClient {
+            client_canister_id,
+            sig,
+        },
+    )))
+    .await?;
+
+    let client = WsClient {
+        client_id,
+        key_pair,
+        sequence_num: 0,
+        sink,
+    };
+    let receiver = Receiver {
+        stream,
+        canister_id,
+        root_key,
+        client_id,
+        next_sequence_num: 0,
+        next_topic_nums: HashMap::new(),
+        resynced: None,
+    };
+    Ok((client, receiver.into_stream()))
+}
+
+async fn ws_register(
+    agent: &Agent,
+    canister_id: &Principal,
+    public_key: Vec<u8>,
+) -> Result<u64, ClientError> {
+    let res = agent
+        .update(canister_id, "ws_register")
+        .with_arg(Encode!(&public_key)?)
+        .call_and_wait()
+        .await?;
+
+    Decode!(&res, Result<u64, WsError>)?.map_err(ClientError::Register)
+}
+
+struct Receiver<S> {
+    stream: S,
+    canister_id: Principal,
+    root_key: Vec<u8>,
+    client_id: u64,
+    next_sequence_num: u64,
+    // Messages published to a topic are numbered per topic, starting from the first one received.
+    next_topic_nums: HashMap<String, u64>,
+    // The message after a gap in the numbering, yielded after the error reporting the gap.
+    resynced: Option<WebsocketMessage>,
+}
+
+impl<S> Receiver<S>
+where
+    S: Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+{
+    fn into_stream(self) -> impl Stream<Item = Result<WebsocketMessage, ClientError>> {
+        stream::unfold(self, |mut receiver| async move {
+            if let Some(message) = receiver.resynced.take() {
+                return Some((Ok(message), receiver));
+            }
+            loop {
+                let result = match receiver.stream.next().await? {
+                    Ok(tungstenite::Message::Binary(bytes)) => receiver.check(&bytes),
+                    Ok(tungstenite::Message::Close(Some(frame)))
+                        if frame.code != CloseCode::Normal =>
+                    {
+                        Err(ClientError::Closed {
+                            code: frame.code.into(),
+                            reason: frame.reason.into_owned(),
+                        })
+                    }
+                    Ok(tungstenite::Message::Close(_)) => return None,
+                    Ok(_) => continue,
+                    Err(e) => Err(e.into()),
+                };
+                return Some((result, receiver));
+            }
+        })
+    }
+
+    fn check(&mut self, bytes: &[u8]) -> Result<WebsocketMessage, ClientError> {
+        let cert_message: CertMessage = decode(bytes)?;
+        verify_cert_message(&cert_message, &self.canister_id, &self.root_key)?;
+        let message: WebsocketMessage = decode(&cert_message.val)?;
+
+        // Messages published to a topic are numbered per topic, from the first one received.
+        let expected = match &message.topic {
+            Some(topic) => self.next_topic_nums.get(topic).copied(),
+            None if message.client_id != self.client_id => {
+                return Err(ClientError::WrongClient(message.client_id));
+            }
+            None => Some(self.next_sequence_num),
+        };
+        if let Some(expected) = expected.filter(|&expected| message.sequence_num < expected) {
+            return Err(ClientError::BadSequence {
+                expected,
+                received: message.sequence_num,
+            });
+        }
+        match &message.topic {
+            Some(topic) => {
+                self.next_topic_nums
+                    .insert(topic.clone(), message.sequence_num + 1);
+            }
+            None => self.next_sequence_num = message.sequence_num + 1,
+        }
+        // Messages that expired in the canister before they were delivered are missed, as are the
+        // messages published to a topic while the client was not subscribed. The numbering resumes
+        // from this message.
+        if let Some(expected) = expected.filter(|&expected| message.sequence_num > expected) {
+            let received = message.sequence_num;
+            self.resynced = Some(message);
+            return Err(ClientError::BadSequence { expected, received });
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_websocket_protocol::{
+        message_key,
+        test_utils::{self, der_public_key},
+    };
+
+    const CLIENT_ID: u64 = 16;
+    const ROOT_KEY: u64 = 42;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("bw4dl-smaaa-aaaaa-qaacq-cai").unwrap()
+    }
+
+    // A message certified by the canister the way the gateway relays it.
+    fn cert_message(message: WebsocketMessage, nonce: u64) -> tungstenite::Message {
+        let key = message_key("2vxsx-fae", nonce);
+        let cert_message =
+            test_utils::cert_message(&canister_id(), key, encode(&message), ROOT_KEY);
+        tungstenite::Message::Binary(encode(&cert_message))
+    }
+
+    fn client_message(client_id: u64, sequence_num: u64) -> WebsocketMessage {
+        WebsocketMessage {
+            client_id,
+            sequence_num,
+            timestamp: 1_680_000_000_000_000_000,
+            message: vec![0, 1, 2, 255],
+            topic: None,
+        }
+    }
+
+    fn topic_message(topic: &str, sequence_num: u64) -> WebsocketMessage {
+        WebsocketMessage {
+            client_id: 0,
+            topic: Some(topic.to_string()),
+            ..client_message(0, sequence_num)
+        }
+    }
+
+    // The results of receiving the messages, certified with the root key, in order.
+    async fn receive_with_root_key(
+        messages: Vec<WebsocketMessage>,
+        root_key: u64,
+    ) -> Vec<Result<WebsocketMessage, ClientError>> {
+        let frames: Vec<_> = messages
+            .into_iter()
+            .enumerate()
+            .map(|(nonce, message)| cert_message(message, nonce as u64))
+            .collect();
+        let receiver = Receiver {
+            stream: stream::iter(frames).map(Ok),
+            canister_id: canister_id(),
+            root_key: der_public_key(root_key),
+            client_id: CLIENT_ID,
+            next_sequence_num: 0,
+            next_topic_nums: HashMap::new(),
+            resynced: None,
+        };
+        receiver.into_stream().collect().await
+    }
+
+    async fn receive(
+        messages: Vec<WebsocketMessage>,
+    ) -> Vec<Result<WebsocketMessage, ClientError>> {
+        receive_with_root_key(messages, ROOT_KEY).await
+    }
+
+    fn sequence_nums(results: &[Result<WebsocketMessage, ClientError>]) -> Vec<Option<u64>> {
+        results
+            .iter()
+            .map(|result| result.as_ref().ok().map(|message| message.sequence_num))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn messages_in_order() {
+        let results = receive((0..3).map(|num| client_message(CLIENT_ID, num)).collect()).await;
+        assert_eq!(sequence_nums(&results), vec![Some(0), Some(1), Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn message_for_another_client() {
+        let results = receive(vec![client_message(CLIENT_ID + 1, 0)]).await;
+        assert!(matches!(results[..], [Err(ClientError::WrongClient(17))]));
+    }
+
+    #[tokio::test]
+    async fn message_out_of_order() {
+        let results = receive(vec![
+            client_message(CLIENT_ID, 0),
+            client_message(CLIENT_ID, 2),
+            client_message(CLIENT_ID, 1),
+        ])
+        .await;
+        assert!(matches!(
+            results[1],
+            Err(ClientError::BadSequence {
+                expected: 1,
+                received: 2
+            })
+        ));
+        assert!(matches!(
+            results[3],
+            Err(ClientError::BadSequence {
+                expected: 3,
+                received: 1
+            })
+        ));
+        // The gap is reported and followed by the message after it, the older message is refused.
+        assert_eq!(sequence_nums(&results), vec![Some(0), None, Some(2), None]);
+    }
+
+    #[tokio::test]
+    async fn message_certified_with_another_key() {
+        let results = receive_with_root_key(vec![client_message(CLIENT_ID, 0)], 43).await;
+        assert!(matches!(
+            results[..],
+            [Err(ClientError::Verify(VerifyError::BadSignature))]
+        ));
+    }
+
+    #[tokio::test]
+    async fn topic_numbering_starts_from_first_message() {
+        let results = receive(vec![
+            topic_message("news", 5),
+            client_message(CLIENT_ID, 0),
+            topic_message("news", 6),
+            topic_message("weather", 0),
+        ])
+        .await;
+        assert_eq!(
+            sequence_nums(&results),
+            vec![Some(5), Some(0), Some(6), Some(0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn topic_resumes_after_gap() {
+        let results = receive(vec![
+            topic_message("news", 5),
+            topic_message("news", 8),
+            topic_message("news", 9),
+            topic_message("news", 9),
+        ])
+        .await;
+        // The gap is reported and followed by the message after it, the repeated message is refused.
+        assert!(matches!(
+            results[1],
+            Err(ClientError::BadSequence {
+                expected: 6,
+                received: 8
+            })
+        ));
+        assert!(matches!(
+            results[4],
+            Err(ClientError::BadSequence {
+                expected: 10,
+                received: 9
+            })
+        ));
+        assert_eq!(
+            sequence_nums(&results),
+            vec![Some(5), None, Some(8), Some(9), None]
+        );
+    }
+}
@@ -1,124 +1,187 @@
-use candid::CandidType;
 use candid::Decode;
 use ed25519_compact::PublicKey;
 use ic_agent::{
     agent::http_transport::ReqwestHttpReplicaV2Transport, export::Principal,
-    identity::BasicIdentity, Agent,
+    identity::BasicIdentity, Agent, RequestId,
 };
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-
-#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq)]
-#[candid_path("ic_cdk::export::candid")]
-pub struct WebsocketMessage {
-    pub client_id: u64,
-    pub sequence_num: u64,
-    pub timestamp: u64,
-    #[serde(with = "serde_bytes")]
-    pub message: Vec<u8>,
+use ic_websocket_protocol::{CertMessages, WsError};
+use std::{fmt, time::Instant};
+
+use crate::metrics;
+
+// Create the agent shared by the sessions and pollers, which fetches the root key once if asked to.
+// Clones of the agent share its transport and connection pool.
+pub async fn create_agent(
+    url: &str,
+    identity: BasicIdentity,
+    fetch_key: bool,
+) -> Result<Agent, String> {
+    let transport = ReqwestHttpReplicaV2Transport::create(url.to_string())
+        .map_err(|e| format!("invalid replica url {}: {}", url, e))?;
+    let agent = Agent::builder()
+        .with_transport(transport)
+        .with_identity(identity)
+        .build()
+        .map_err(|e| format!("could not create the agent: {}", e))?;
+    if fetch_key {
+        agent
+            .fetch_root_key()
+            .await
+            .map_err(|e| format!("could not fetch the root key from {}: {}", url, e))?;
+    }
+    Ok(agent)
 }
 
-#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq)]
-pub struct EncodedMessage {
-    pub client_id: u64,
-    pub key: String,
-    #[serde(with = "serde_bytes")]
-    pub val: Vec<u8>,
+// Errors of the calls to the websocket endpoints of a canister.
+#[derive(Debug)]
+pub enum CallError {
+    // The call did not reach the canister or its reply could not be decoded.
+    Transport(String),
+    // The canister returned an error.
+    Canister(WsError),
 }
 
-#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq)]
-pub struct CertMessages {
-    pub messages: Vec<EncodedMessage>,
-    #[serde(with = "serde_bytes")]
-    pub cert: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    pub tree: Vec<u8>,
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Transport(e) => write!(f, "{}", e),
+            CallError::Canister(e) => write!(f, "canister returned {:?}", e),
+        }
+    }
 }
 
-pub async fn get_new_agent(url: &str, identity: Arc<BasicIdentity>, fetch_key: bool) -> Agent {
-    let transport = ReqwestHttpReplicaV2Transport::create(url.to_string()).unwrap();
-    let agent = Agent::builder()
-        .with_transport(transport)
-        .with_arc_identity(identity)
-        .build()
-        .unwrap();
-    if fetch_key {
-        agent.fetch_root_key().await.unwrap();
-    }
-    agent
+fn transport_error(e: impl ToString) -> CallError {
+    CallError::Transport(e.to_string())
 }
 
 pub async fn ws_get_client_key(
     agent: &Agent,
     canister_id: &Principal,
     client_id: u64,
-) -> PublicKey {
-    let args = candid::encode_args((client_id,))
-        .map_err(|e| e.to_string())
-        .unwrap();
+) -> Result<PublicKey, CallError> {
+    let args = candid::encode_args((client_id,)).map_err(transport_error)?;
 
+    let start = Instant::now();
     let res = agent
         .query(canister_id, "ws_get_client_key")
         .with_arg(&args)
         .call()
-        .await
-        .unwrap();
-
-    PublicKey::from_slice(&Decode!(&res, Vec<u8>).map_err(|e| e.to_string()).unwrap()).unwrap()
+        .await;
+    metrics::observe_call("ws_get_client_key", start);
+    let res = res.map_err(transport_error)?;
+
+    Decode!(&res, Result<Vec<u8>, WsError>)
+        .map_err(transport_error)?
+        .and_then(|key| PublicKey::from_slice(&key).map_err(|_| WsError::InvalidPublicKey))
+        .map_err(CallError::Canister)
 }
 
-pub async fn ws_open(agent: &Agent, canister_id: &Principal, msg: Vec<u8>, sig: Vec<u8>) -> bool {
-    let args = candid::encode_args((msg, sig)).unwrap();
+pub async fn ws_open(
+    agent: &Agent,
+    canister_id: &Principal,
+    msg: Vec<u8>,
+    sig: Vec<u8>,
+) -> Result<(), CallError> {
+    let args = candid::encode_args((msg, sig)).map_err(transport_error)?;
 
+    let start = Instant::now();
     let res = agent
         .update(canister_id, "ws_open")
         .with_arg(args)
         .call_and_wait()
-        .await
-        .unwrap();
+        .await;
+    metrics::observe_call("ws_open", start);
+    let res = res.map_err(transport_error)?;
 
-    Decode!(&res, bool).map_err(|e| e.to_string()).unwrap()
+    Decode!(&res, Result<(), WsError>)
+        .map_err(transport_error)?
+        .map_err(CallError::Canister)
 }
 
-pub async fn ws_close(agent: &Agent, canister_id: &Principal, can_client_id: u64) {
-    let args = candid::encode_args((can_client_id,)).unwrap();
+pub async fn ws_close(
+    agent: &Agent,
+    canister_id: &Principal,
+    can_client_id: u64,
+) -> Result<(), CallError> {
+    let args = candid::encode_args((can_client_id,)).map_err(transport_error)?;
 
+    let start = Instant::now();
     let res = agent
         .update(canister_id, "ws_close")
         .with_arg(args)
         .call_and_wait()
+        .await;
+    metrics::observe_call("ws_close", start);
+    let res = res.map_err(transport_error)?;
+
+    Decode!(&res, Result<(), WsError>)
+        .map_err(transport_error)?
+        .map_err(CallError::Canister)
+}
+
+// Submit a client message without waiting for the canister to process it, so that the next one
+// can be submitted right away.
+pub async fn submit_ws_message(
+    agent: &Agent,
+    canister_id: &Principal,
+    mes: Vec<u8>,
+) -> Result<RequestId, CallError> {
+    let args = candid::encode_args((mes,)).map_err(transport_error)?;
+
+    agent
+        .update(canister_id, "ws_message")
+        .with_arg(args)
+        .call()
         .await
-        .unwrap();
+        .map_err(transport_error)
+}
 
-    Decode!(&res, ()).map_err(|e| e.to_string()).unwrap()
+// Wait for the reply to a client message submitted at submitted.
+pub async fn wait_ws_message(
+    agent: &Agent,
+    canister_id: &Principal,
+    request_id: RequestId,
+    submitted: Instant,
+) -> Result<(), CallError> {
+    let res = agent.wait(request_id, *canister_id).await;
+    metrics::observe_call("ws_message", submitted);
+    let res = res.map_err(transport_error)?;
+
+    Decode!(&res, Result<(), WsError>)
+        .map_err(transport_error)?
+        .map_err(CallError::Canister)
 }
 
-pub async fn ws_message(agent: &Agent, canister_id: &Principal, mes: Vec<u8>) -> bool {
-    let args = candid::encode_args((mes,)).unwrap();
+pub async fn ws_ack(agent: &Agent, canister_id: &Principal, nonce: u64) -> Result<(), CallError> {
+    let args = candid::encode_args((nonce,)).map_err(transport_error)?;
 
+    let start = Instant::now();
     let res = agent
-        .update(canister_id, "ws_message")
+        .update(canister_id, "ws_ack")
         .with_arg(args)
         .call_and_wait()
-        .await
-        .unwrap();
+        .await;
+    metrics::observe_call("ws_ack", start);
+    let res = res.map_err(transport_error)?;
 
-    Decode!(&res, bool).map_err(|e| e.to_string()).unwrap()
+    Decode!(&res, ()).map_err(transport_error)
 }
 
-pub async fn ws_get_messages(agent: &Agent, canister_id: &Principal, nonce: u64) -> CertMessages {
-    let args = candid::encode_args((nonce,))
-        .map_err(|e| e.to_string())
-        .unwrap();
+pub async fn ws_get_messages(
+    agent: &Agent,
+    canister_id: &Principal,
+    nonce: u64,
+) -> Result<CertMessages, CallError> {
+    let args = candid::encode_args((nonce,)).map_err(transport_error)?;
 
+    let start = Instant::now();
     let res = agent
         .query(canister_id, "ws_get_messages")
         .with_arg(&args)
         .call()
-        .await
-        .unwrap();
+        .await;
+    metrics::observe_call("ws_get_messages", start);
+    let res = res.map_err(transport_error)?;
 
-    Decode!(&res, CertMessages)
-        .map_err(|e| e.to_string())
-        .unwrap()
+    Decode!(&res, CertMessages).map_err(transport_error)
 }
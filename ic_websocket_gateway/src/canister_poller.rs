@@ -0,0 +1,329 @@
+use ic_agent::{export::Principal, Agent};
+use ic_websocket_protocol::{
+    encode, encode_json, message_nonce, verify_witness, CertMessage, CertMessages, JsonCertMessage,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{canister_methods, config::Config, metrics, Encoding, Session};
+
+// Failed polls are retried after the polling interval, doubled with every further failure up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// The delivered messages are acknowledged once this many are waiting for it, or after ACK_INTERVAL,
+// rather than with an update call after every poll.
+const ACK_BATCH: u64 = 100;
+const ACK_INTERVAL: Duration = Duration::from_secs(10);
+// The canister expires the websockets of a gateway that has not called it for 30 minutes. The nonce
+// is acknowledged at least this often, so that clients that only receive messages stay connected.
+const ACK_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Whether the canister could be polled lately.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PollerHealth {
+    // No poll has completed yet.
+    Starting,
+    Healthy,
+    // The last polls failed, they are retried with backoff.
+    Unhealthy { failures: u32, last_error: String },
+}
+
+#[derive(Debug)]
+pub struct CanisterPoller {
+    canister_id: String,
+    canister_client_session_map: Arc<Mutex<HashMap<u64, (Session, Encoding)>>>,
+    agent: Agent,
+    config: Arc<Config>,
+    // The polling task, only running while clients of the canister are connected.
+    polling_task: Option<JoinHandle<()>>,
+    // Nonce of the next message to poll for, kept when polling stops so that it resumes from there.
+    next_nonce: Arc<AtomicU64>,
+    health: Arc<Mutex<PollerHealth>>,
+}
+
+impl CanisterPoller {
+    pub fn new(canister_id: String, agent: Agent, config: Arc<Config>) -> Self {
+        CanisterPoller {
+            canister_id,
+            canister_client_session_map: Arc::new(Mutex::new(HashMap::new())),
+            agent,
+            config,
+            polling_task: None,
+            next_nonce: Arc::new(AtomicU64::new(0)),
+            health: Arc::new(Mutex::new(PollerHealth::Starting)),
+        }
+    }
+
+    pub fn is_polling(&self) -> bool {
+        self.polling_task.is_some()
+    }
+
+    pub fn health(&self) -> PollerHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    pub fn start_polling(&mut self) {
+        info!("Start of polling canister {}.", self.canister_id);
+        let polling = Polling {
+            canister_id: Principal::from_text(&self.canister_id).unwrap(),
+            root_key: self.agent.read_root_key().unwrap(),
+            agent: self.agent.clone(),
+            sessions: Arc::clone(&self.canister_client_session_map),
+            verify_certificates: self.config.verify_certificates,
+            next_nonce: Arc::clone(&self.next_nonce),
+            health: Arc::clone(&self.health),
+        };
+        let span = info_span!("poller", canister_id = %self.canister_id);
+        self.polling_task = Some(tokio::spawn(
+            polling.run(self.config.polling_interval).instrument(span),
+        ));
+    }
+
+    pub fn stop_polling(&mut self) {
+        if let Some(task) = self.polling_task.take() {
+            info!("End of polling canister {}.", self.canister_id);
+            task.abort();
+            let _ = metrics::NONCE_LAG.remove_label_values(&[&self.canister_id]);
+        }
+    }
+
+    pub fn add_session(&self, canister_client_id: u64, session: Session, encoding: Encoding) {
+        let map = &self.canister_client_session_map;
+        let mut m = map.lock().unwrap();
+        m.insert(canister_client_id, (session, encoding));
+    }
+
+    pub fn has_session(&self, canister_client_id: u64) -> bool {
+        self.canister_client_session_map
+            .lock()
+            .unwrap()
+            .contains_key(&canister_client_id)
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.canister_client_session_map.lock().unwrap().len()
+    }
+
+    // Returns whether sessions of the canister are left.
+    pub fn remove_session(&self, canister_client_id: u64) -> bool {
+        let map = &self.canister_client_session_map;
+        let mut m = map.lock().unwrap();
+        m.remove(&canister_client_id);
+        !m.is_empty()
+    }
+}
+
+// Whether to acknowledge the nonce, unacked messages having been delivered since the last ws_ack
+// elapsed ago.
+fn ack_due(unacked: u64, elapsed: Duration) -> bool {
+    unacked >= ACK_BATCH
+        || (unacked > 0 && elapsed >= ACK_INTERVAL)
+        || elapsed >= ACK_KEEPALIVE_INTERVAL
+}
+
+// State of the polling task of a canister.
+struct Polling {
+    canister_id: Principal,
+    root_key: Vec<u8>,
+    agent: Agent,
+    sessions: Arc<Mutex<HashMap<u64, (Session, Encoding)>>>,
+    verify_certificates: bool,
+    next_nonce: Arc<AtomicU64>,
+    health: Arc<Mutex<PollerHealth>>,
+}
+
+impl Polling {
+    async fn run(self, interval: Duration) {
+        let mut nonce = self.next_nonce.load(Ordering::SeqCst);
+        let mut acked_nonce = nonce;
+        let mut acked_at = Instant::now();
+        let mut ack: Option<JoinHandle<()>> = None;
+        let mut failures: u32 = 0;
+        let canister_label = self.canister_id.to_text();
+        let nonce_lag = metrics::NONCE_LAG.with_label_values(&[&canister_label]);
+        let polling_errors = metrics::POLLING_ERRORS.with_label_values(&[&canister_label]);
+        loop {
+            match canister_methods::ws_get_messages(&self.agent, &self.canister_id, nonce).await {
+                Ok(msgs) => {
+                    if failures > 0 {
+                        info!("Polling recovered after {} failures.", failures);
+                        failures = 0;
+                    }
+                    self.set_health(PollerHealth::Healthy);
+                    // Older canisters do not report the messages left after the batch.
+                    if let Some(remaining) = msgs.remaining {
+                        nonce_lag.set(remaining as i64);
+                    }
+
+                    nonce = self.relay_messages(msgs, nonce);
+                    self.next_nonce.store(nonce, Ordering::SeqCst);
+
+                    // Let the canister delete the messages that were delivered, one ws_ack at a time.
+                    let ack_idle = ack.as_ref().is_none_or(|ack| ack.is_finished());
+                    if ack_idle && ack_due(nonce - acked_nonce, acked_at.elapsed()) {
+                        acked_nonce = nonce;
+                        acked_at = Instant::now();
+                        let agent = self.agent.clone();
+                        let canister_id = self.canister_id;
+                        ack = Some(tokio::spawn(
+                            async move {
+                                if let Err(e) =
+                                    canister_methods::ws_ack(&agent, &canister_id, nonce).await
+                                {
+                                    // The messages expire in the canister anyway.
+                                    warn!("ws_ack failed: {}", e);
+                                }
+                            }
+                            .in_current_span(),
+                        ));
+                    }
+
+                    tokio::time::sleep(interval).await;
+                }
+                Err(e) => {
+                    failures += 1;
+                    polling_errors.inc();
+                    let backoff = interval
+                        .saturating_mul(2u32.saturating_pow((failures - 1).min(16)))
+                        .min(MAX_BACKOFF);
+                    warn!(
+                        "Polling failed ({} in a row), retrying in {:?}: {}",
+                        failures, backoff, e
+                    );
+                    self.set_health(PollerHealth::Unhealthy {
+                        failures,
+                        last_error: e.to_string(),
+                    });
+
+                    tokio::time::sleep(backoff).await;
+                }
+            }
+        }
+    }
+
+    fn set_health(&self, health: PollerHealth) {
+        *self.health.lock().unwrap() = health;
+    }
+
+    // Forward the polled messages to the connected clients and return the nonce to poll for next.
+    fn relay_messages(&self, msgs: CertMessages, mut nonce: u64) -> u64 {
+        // The messages of a batch share their certificate and witness, which are verified once.
+        let witness = if self.verify_certificates && !msgs.messages.is_empty() {
+            Some(verify_witness(
+                &msgs.cert,
+                &msgs.tree,
+                &self.canister_id,
+                &self.root_key,
+            ))
+        } else {
+            None
+        };
+        let to_client = metrics::MESSAGES.with_label_values(&[metrics::TO_CLIENT]);
+        for encoded_message in msgs.messages {
+            let message_nonce = match message_nonce(&encoded_message.key) {
+                Some(message_nonce) => message_nonce,
+                None => {
+                    warn!(
+                        "Skipping message with malformed key {}.",
+                        encoded_message.key
+                    );
+                    continue;
+                }
+            };
+            nonce = nonce.max(message_nonce + 1);
+
+            // Messages published to a topic are fanned out to all its subscribers.
+            let recipients = match encoded_message.subscribers {
+                Some(subscribers) => {
+                    debug!(
+                        "Message to {} subscribers with key {}.",
+                        subscribers.len(),
+                        encoded_message.key
+                    );
+                    subscribers
+                }
+                None => {
+                    debug!(
+                        "Message to client #{} with key {}.",
+                        encoded_message.client_id, encoded_message.key
+                    );
+                    vec![encoded_message.client_id]
+                }
+            };
+
+            let m = CertMessage {
+                key: encoded_message.key,
+                val: encoded_message.val,
+                cert: msgs.cert.clone(),
+                tree: msgs.tree.clone(),
+            };
+            let verified = match &witness {
+                None => Ok(()),
+                Some(Ok(witness)) => witness
+                    .verify_message(&m.key, &m.val)
+                    .map_err(|e| e.to_string()),
+                Some(Err(e)) => Err(e.to_string()),
+            };
+            if let Err(e) = verified {
+                warn!("Skipping message with key {}: {}", m.key, e);
+                continue;
+            }
+            let bytes = encode(&m);
+            // Only encoded if a client uses JSON.
+            let mut text = None;
+
+            // The sessions are only locked to look them up, not while sending.
+            let sessions: Vec<_> = {
+                let map = self.sessions.lock().unwrap();
+                recipients
+                    .into_iter()
+                    .map(|client_id| (client_id, map.get(&client_id).cloned()))
+                    .collect()
+            };
+            for (client_id, session) in sessions {
+                // The client may have disconnected, or connected through a previous run of the gateway.
+                let sent = match session {
+                    Some((s, Encoding::Cbor)) => s.binary(bytes.clone()).is_ok(),
+                    Some((s, Encoding::Json)) => {
+                        let text = text
+                            .get_or_insert_with(|| encode_json(&JsonCertMessage::from(m.clone())));
+                        s.text(text.clone()).is_ok()
+                    }
+                    None => false,
+                };
+                if sent {
+                    to_client.inc();
+                } else {
+                    info!(
+                        "Skipping message with key {} for client #{}, which is not connected.",
+                        m.key, client_id
+                    );
+                }
+            }
+        }
+        nonce
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_are_batched() {
+        // A batch of messages is acknowledged right away, fewer messages after ACK_INTERVAL.
+        assert!(ack_due(ACK_BATCH, Duration::ZERO));
+        assert!(!ack_due(ACK_BATCH - 1, ACK_INTERVAL / 2));
+        assert!(ack_due(1, ACK_INTERVAL));
+        // Without messages, the nonce is only acknowledged to keep the websockets from expiring.
+        assert!(!ack_due(0, ACK_INTERVAL));
+        assert!(ack_due(0, ACK_KEEPALIVE_INTERVAL));
+    }
+}
@@ -0,0 +1,618 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use ic_agent::export::Principal;
+use serde::Deserialize;
+use std::{collections::HashSet, fmt, fs, net::SocketAddr, path::PathBuf, time::Duration};
+use tracing::Level;
+
+// Gateway settings, taken in this order from the command line, the environment, the config file
+// and the defaults below.
+const DEFAULT_URL: &str = "http://127.0.0.1:4943";
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_POLLING_INTERVAL_MS: u64 = 200;
+const DEFAULT_WORKER_THREADS: usize = 10;
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_MAX_IN_FLIGHT_MESSAGES: usize = 16;
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+// Hosts of the IC mainnet, whose root key is known and must never be fetched.
+const MAINNET_HOSTS: [&str; 2] = ["ic0.app", "icp0.io"];
+
+#[derive(Parser, Debug)]
+#[command(about = "Gateway relaying websockets between clients and IC canisters")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_CONFIG",
+        help = "TOML file with any of the settings below, which the flags and environment variables override."
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_URL",
+        help = "URL of the replica the canisters are called through, e.g. https://icp0.io for mainnet."
+    )]
+    url: Option<String>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_FETCH_ROOT_KEY",
+        help = "Fetch the root key from the replica instead of using the mainnet one. Only for local replicas. Defaults to true for a replica on localhost."
+    )]
+    fetch_root_key: Option<bool>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_LISTEN_ADDRESS",
+        help = "Address the websocket server listens on."
+    )]
+    listen_address: Option<SocketAddr>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_POLLING_INTERVAL_MS",
+        help = "Time between two polls of a canister for messages."
+    )]
+    polling_interval_ms: Option<u64>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_WORKER_THREADS",
+        help = "Number of threads of the runtime."
+    )]
+    worker_threads: Option<usize>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_MAX_CONNECTIONS",
+        help = "Maximum number of open websockets. Unlimited if not set."
+    )]
+    max_connections: Option<usize>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_MAX_CONNECTIONS_PER_IP",
+        help = "Maximum number of open websockets from one IP address. Unlimited if not set."
+    )]
+    max_connections_per_ip: Option<usize>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_MAX_SESSIONS_PER_CANISTER",
+        help = "Maximum number of websockets opened with one canister. Unlimited if not set."
+    )]
+    max_sessions_per_canister: Option<usize>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_MAX_MESSAGES_PER_SECOND",
+        help = "Maximum rate of the messages from a client, averaged over the burst. Unlimited if not set."
+    )]
+    max_messages_per_second: Option<u32>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_MESSAGE_BURST",
+        help = "Number of messages a client may send at once within its message rate. Defaults to the rate."
+    )]
+    message_burst: Option<u32>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_MAX_IN_FLIGHT_MESSAGES",
+        help = "Maximum number of messages of a client submitted to the canister and waiting for their replies."
+    )]
+    max_in_flight_messages: Option<usize>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_HANDSHAKE_TIMEOUT_MS",
+        help = "Time a client has to send its first message after connecting."
+    )]
+    handshake_timeout_ms: Option<u64>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_SHUTDOWN_TIMEOUT_MS",
+        help = "Time the websockets have to close on SIGTERM or SIGINT before the gateway exits."
+    )]
+    shutdown_timeout_ms: Option<u64>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_VERIFY_CERTIFICATES",
+        help = "Verify the certificates of canister messages before forwarding them."
+    )]
+    verify_certificates: Option<bool>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_LOG_LEVEL",
+        help = "One of error, warn, info, debug, trace."
+    )]
+    log_level: Option<String>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_LOG_FORMAT",
+        help = "text for human readable logs, json for one JSON object per line."
+    )]
+    log_format: Option<LogFormat>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_IDENTITY_FILE",
+        help = "PEM file with the key of the gateway identity, see generate-identity. A new identity is used on every start if not set."
+    )]
+    identity_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_METRICS_ADDRESS",
+        help = "Address to serve prometheus metrics on, at /metrics. Not served if not set."
+    )]
+    metrics_address: Option<SocketAddr>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_TLS_CERT_FILE",
+        help = "PEM file with the TLS certificate chain, to accept wss:// connections. Reloaded when it changes."
+    )]
+    tls_cert_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_TLS_KEY_FILE",
+        help = "PEM file with the private key of the TLS certificate. Reloaded when it changes."
+    )]
+    tls_key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_ALLOWED_CANISTERS",
+        value_delimiter = ',',
+        help = "Comma separated ids of the only canisters clients may connect to. All canisters if not set."
+    )]
+    allowed_canisters: Option<Vec<String>>,
+    #[arg(
+        long,
+        env = "IC_WS_GATEWAY_DENIED_CANISTERS",
+        value_delimiter = ',',
+        help = "Comma separated ids of canisters clients may not connect to."
+    )]
+    denied_canisters: Option<Vec<String>>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Write a new identity key to a PEM file and print its principal")]
+    GenerateIdentity { path: PathBuf },
+}
+
+// Contents of the config file. All settings are optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    url: Option<String>,
+    fetch_root_key: Option<bool>,
+    listen_address: Option<SocketAddr>,
+    polling_interval_ms: Option<u64>,
+    worker_threads: Option<usize>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_sessions_per_canister: Option<usize>,
+    max_messages_per_second: Option<u32>,
+    message_burst: Option<u32>,
+    max_in_flight_messages: Option<usize>,
+    handshake_timeout_ms: Option<u64>,
+    shutdown_timeout_ms: Option<u64>,
+    verify_certificates: Option<bool>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    identity_file: Option<PathBuf>,
+    metrics_address: Option<SocketAddr>,
+    tls_cert_file: Option<PathBuf>,
+    tls_key_file: Option<PathBuf>,
+    allowed_canisters: Option<Vec<String>>,
+    denied_canisters: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub url: String,
+    pub fetch_root_key: bool,
+    pub listen_address: SocketAddr,
+    pub polling_interval: Duration,
+    pub worker_threads: usize,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_sessions_per_canister: Option<usize>,
+    // Rate and burst of the token bucket limiting the messages of each client.
+    pub message_rate: Option<(u32, u32)>,
+    pub max_in_flight_messages: usize,
+    pub handshake_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub verify_certificates: bool,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub identity_file: Option<PathBuf>,
+    pub metrics_address: Option<SocketAddr>,
+    // Certificate and key files, set together if the websockets use TLS.
+    pub tls: Option<(PathBuf, PathBuf)>,
+    // Canisters clients may connect to, all but the denied ones if None.
+    pub allowed_canisters: Option<HashSet<Principal>>,
+    pub denied_canisters: HashSet<Principal>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // The config file could not be read.
+    Read(PathBuf, std::io::Error),
+    // The config file is not valid TOML or has unknown settings.
+    Parse(PathBuf, toml::de::Error),
+    // A setting has a value that cannot work.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config in {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // Merge the command line and environment with the config file, and check the resulting settings.
+    pub fn from_cli(cli: Cli) -> Result<Config, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => {
+                let contents =
+                    fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let url = cli
+            .url
+            .or(file.url)
+            .unwrap_or_else(|| DEFAULT_URL.to_string());
+        let host = url_host(&url)
+            .ok_or_else(|| ConfigError::Invalid(format!("url {} has no host", url)))?;
+        let is_local = host == "localhost" || host == "127.0.0.1" || host == "[::1]";
+        let is_mainnet = MAINNET_HOSTS
+            .iter()
+            .any(|mainnet| host == *mainnet || host.ends_with(&format!(".{}", mainnet)));
+
+        let fetch_root_key = cli
+            .fetch_root_key
+            .or(file.fetch_root_key)
+            .unwrap_or(is_local);
+        if fetch_root_key && is_mainnet {
+            return Err(ConfigError::Invalid(format!(
+                "fetch_root_key must not be set for the mainnet url {}",
+                url
+            )));
+        }
+
+        let polling_interval_ms = cli
+            .polling_interval_ms
+            .or(file.polling_interval_ms)
+            .unwrap_or(DEFAULT_POLLING_INTERVAL_MS);
+        if polling_interval_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "polling_interval_ms must be positive".to_string(),
+            ));
+        }
+
+        let worker_threads = cli
+            .worker_threads
+            .or(file.worker_threads)
+            .unwrap_or(DEFAULT_WORKER_THREADS);
+        if worker_threads == 0 {
+            return Err(ConfigError::Invalid(
+                "worker_threads must be positive".to_string(),
+            ));
+        }
+
+        let max_connections = cli.max_connections.or(file.max_connections);
+        if max_connections == Some(0) {
+            return Err(ConfigError::Invalid(
+                "max_connections must be positive".to_string(),
+            ));
+        }
+
+        let max_connections_per_ip = cli.max_connections_per_ip.or(file.max_connections_per_ip);
+        if max_connections_per_ip == Some(0) {
+            return Err(ConfigError::Invalid(
+                "max_connections_per_ip must be positive".to_string(),
+            ));
+        }
+
+        let max_sessions_per_canister = cli
+            .max_sessions_per_canister
+            .or(file.max_sessions_per_canister);
+        if max_sessions_per_canister == Some(0) {
+            return Err(ConfigError::Invalid(
+                "max_sessions_per_canister must be positive".to_string(),
+            ));
+        }
+
+        let message_burst = cli.message_burst.or(file.message_burst);
+        let message_rate = match (
+            cli.max_messages_per_second.or(file.max_messages_per_second),
+            message_burst,
+        ) {
+            (Some(0), _) | (_, Some(0)) => {
+                return Err(ConfigError::Invalid(
+                    "max_messages_per_second and message_burst must be positive".to_string(),
+                ))
+            }
+            (Some(rate), burst) => Some((rate, burst.unwrap_or(rate))),
+            (None, Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "message_burst needs max_messages_per_second".to_string(),
+                ))
+            }
+            (None, None) => None,
+        };
+
+        let max_in_flight_messages = cli
+            .max_in_flight_messages
+            .or(file.max_in_flight_messages)
+            .unwrap_or(DEFAULT_MAX_IN_FLIGHT_MESSAGES);
+        if max_in_flight_messages == 0 {
+            return Err(ConfigError::Invalid(
+                "max_in_flight_messages must be positive".to_string(),
+            ));
+        }
+
+        let handshake_timeout_ms = cli
+            .handshake_timeout_ms
+            .or(file.handshake_timeout_ms)
+            .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MS);
+        if handshake_timeout_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "handshake_timeout_ms must be positive".to_string(),
+            ));
+        }
+
+        let shutdown_timeout_ms = cli
+            .shutdown_timeout_ms
+            .or(file.shutdown_timeout_ms)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS);
+        if shutdown_timeout_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "shutdown_timeout_ms must be positive".to_string(),
+            ));
+        }
+
+        let log_level = cli
+            .log_level
+            .or(file.log_level)
+            .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string());
+        let log_level = log_level.parse().map_err(|_| {
+            ConfigError::Invalid(format!(
+                "log_level {} is not one of error, warn, info, debug, trace",
+                log_level
+            ))
+        })?;
+
+        let tls = match (
+            cli.tls_cert_file.or(file.tls_cert_file),
+            cli.tls_key_file.or(file.tls_key_file),
+        ) {
+            (Some(cert_file), Some(key_file)) => Some((cert_file, key_file)),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "tls_cert_file and tls_key_file must be set together".to_string(),
+                ))
+            }
+        };
+
+        let allowed_canisters = cli
+            .allowed_canisters
+            .or(file.allowed_canisters)
+            .map(|ids| parse_canister_ids("allowed_canisters", ids))
+            .transpose()?;
+        let denied_canisters = cli
+            .denied_canisters
+            .or(file.denied_canisters)
+            .map(|ids| parse_canister_ids("denied_canisters", ids))
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Config {
+            url,
+            fetch_root_key,
+            listen_address: cli
+                .listen_address
+                .or(file.listen_address)
+                .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.parse().unwrap()),
+            polling_interval: Duration::from_millis(polling_interval_ms),
+            worker_threads,
+            max_connections,
+            max_connections_per_ip,
+            max_sessions_per_canister,
+            message_rate,
+            max_in_flight_messages,
+            handshake_timeout: Duration::from_millis(handshake_timeout_ms),
+            shutdown_timeout: Duration::from_millis(shutdown_timeout_ms),
+            verify_certificates: cli
+                .verify_certificates
+                .or(file.verify_certificates)
+                .unwrap_or(false),
+            log_level,
+            log_format: cli.log_format.or(file.log_format).unwrap_or_default(),
+            identity_file: cli.identity_file.or(file.identity_file),
+            metrics_address: cli.metrics_address.or(file.metrics_address),
+            tls,
+            allowed_canisters,
+            denied_canisters,
+        })
+    }
+
+    pub fn is_canister_allowed(&self, canister_id: &Principal) -> bool {
+        let allowed = match &self.allowed_canisters {
+            Some(allowed_canisters) => allowed_canisters.contains(canister_id),
+            None => true,
+        };
+        allowed && !self.denied_canisters.contains(canister_id)
+    }
+}
+
+fn parse_canister_ids(setting: &str, ids: Vec<String>) -> Result<HashSet<Principal>, ConfigError> {
+    ids.iter()
+        .map(|id| {
+            Principal::from_text(id.trim()).map_err(|e| {
+                ConfigError::Invalid(format!(
+                    "{} has an invalid canister id {}: {}",
+                    setting, id, e
+                ))
+            })
+        })
+        .collect()
+}
+
+// The host of an http(s) URL, without the port.
+fn url_host(url: &str) -> Option<&str> {
+    let rest = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))?;
+    let authority = rest.split('/').next()?;
+    let host = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => &authority[..i],
+        _ => authority,
+    };
+    (!host.is_empty()).then_some(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Result<Config, ConfigError> {
+        let cli = Cli::try_parse_from(["ic_websocket_gateway"].iter().chain(args)).unwrap();
+        Config::from_cli(cli)
+    }
+
+    // Write the config file of a test, named after it so that the tests can run in parallel.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "ic_ws_gateway_{}_{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn assert_invalid(args: &[&str]) {
+        assert!(matches!(config(args), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config.url, DEFAULT_URL);
+        // The default replica is local, its root key is fetched.
+        assert!(config.fetch_root_key);
+        assert_eq!(config.listen_address, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.polling_interval, Duration::from_millis(200));
+        assert_eq!(config.max_in_flight_messages, 16);
+        assert_eq!(config.message_rate, None);
+        assert_eq!(config.log_level, Level::INFO);
+        assert!(config.tls.is_none());
+        assert!(config.allowed_canisters.is_none());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = config_file(
+            "flags_override_the_config_file",
+            "polling_interval_ms = 500\nworker_threads = 4\nmax_messages_per_second = 10\n",
+        );
+        let config = config(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--polling-interval-ms",
+            "300",
+        ])
+        .unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(config.polling_interval, Duration::from_millis(300));
+        assert_eq!(config.worker_threads, 4);
+        // The burst defaults to the rate.
+        assert_eq!(config.message_rate, Some((10, 10)));
+    }
+
+    #[test]
+    fn unknown_setting_in_the_config_file() {
+        let path = config_file(
+            "unknown_setting_in_the_config_file",
+            "polling_interval = 500\n",
+        );
+        let result = config(&["--config", path.to_str().unwrap()]);
+        fs::remove_file(path).unwrap();
+        assert!(matches!(result, Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn root_key_is_only_fetched_by_default_from_local_replicas() {
+        assert!(
+            !config(&["--url", "https://icp0.io"])
+                .unwrap()
+                .fetch_root_key
+        );
+        assert!(
+            config(&["--url", "http://localhost:8000"])
+                .unwrap()
+                .fetch_root_key
+        );
+        assert_invalid(&["--url", "https://icp0.io", "--fetch-root-key", "true"]);
+        assert_invalid(&[
+            "--url",
+            "https://xyz.raw.ic0.app",
+            "--fetch-root-key",
+            "true",
+        ]);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert_invalid(&["--url", "icp0.io"]);
+        assert_invalid(&["--polling-interval-ms", "0"]);
+        assert_invalid(&["--max-sessions-per-canister", "0"]);
+        assert_invalid(&["--message-burst", "5"]);
+        assert_invalid(&["--max-messages-per-second", "10", "--message-burst", "0"]);
+        assert_invalid(&["--max-in-flight-messages", "0"]);
+        assert_invalid(&["--log-level", "verbose"]);
+        assert_invalid(&["--tls-cert-file", "cert.pem"]);
+        assert_invalid(&["--allowed-canisters", "not-a-canister"]);
+    }
+
+    #[test]
+    fn allowed_and_denied_canisters() {
+        let allowed = "bw4dl-smaaa-aaaaa-qaacq-cai";
+        let denied = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+        let other = Principal::from_text("2vxsx-fae").unwrap();
+
+        let denying = config(&["--denied-canisters", denied]).unwrap();
+        assert!(denying.is_canister_allowed(&Principal::from_text(allowed).unwrap()));
+        assert!(!denying.is_canister_allowed(&Principal::from_text(denied).unwrap()));
+
+        let list = format!("{}, {}", allowed, denied);
+        let allowing =
+            config(&["--allowed-canisters", &list, "--denied-canisters", denied]).unwrap();
+        assert!(allowing.is_canister_allowed(&Principal::from_text(allowed).unwrap()));
+        assert!(!allowing.is_canister_allowed(&Principal::from_text(denied).unwrap()));
+        assert!(!allowing.is_canister_allowed(&other));
+    }
+
+    #[test]
+    fn host_of_url() {
+        assert_eq!(url_host("http://127.0.0.1:4943"), Some("127.0.0.1"));
+        assert_eq!(url_host("https://icp0.io"), Some("icp0.io"));
+        assert_eq!(url_host("https://icp0.io/api/v2"), Some("icp0.io"));
+        assert_eq!(url_host("http://[::1]:4943/"), Some("[::1]"));
+        assert_eq!(url_host("http://[::1]"), Some("[::1]"));
+        assert_eq!(url_host("http://"), None);
+        assert_eq!(url_host("ws://127.0.0.1:4943"), None);
+    }
+}
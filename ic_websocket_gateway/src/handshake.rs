@@ -0,0 +1,141 @@
+use ed25519_compact::Signature;
+use ezsockets::{CloseCode, CloseFrame};
+use ic_agent::{export::Principal, Agent};
+use ic_websocket_protocol::{decode, FirstMessage, FirstMessageFromClient, WsError};
+use std::fmt;
+
+use crate::{
+    canister_methods::{self, CallError},
+    config::Config,
+};
+
+// Close codes of the websockets rejected during the handshake, from the range reserved for applications.
+pub const CLOSE_HANDSHAKE_TIMEOUT: u16 = 4000;
+pub const CLOSE_BAD_FORMAT: u16 = 4001;
+pub const CLOSE_BAD_SIGNATURE: u16 = 4002;
+pub const CLOSE_UNKNOWN_CLIENT: u16 = 4003;
+pub const CLOSE_OPEN_REFUSED: u16 = 4004;
+pub const CLOSE_CANISTER_NOT_ALLOWED: u16 = 4005;
+
+// Reasons to reject the first message of a client.
+#[derive(Debug)]
+pub enum HandshakeError {
+    // The client did not send its first message in time.
+    Timeout,
+    // The first message, its content or the canister id could not be decoded.
+    BadFormat(String),
+    // The first message is not signed with the key the client registered.
+    BadSignature,
+    // The gateway does not relay websockets of the canister.
+    CanisterNotAllowed(Principal),
+    // The canister does not know the client.
+    UnknownClient(u64),
+    // The canister refused the websocket for another reason.
+    OpenRefused(WsError),
+    // The canister could not be called.
+    Transport(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Transport(e) => write!(f, "could not call the canister: {}", e),
+            _ => write!(f, "{}", self.close_frame().reason),
+        }
+    }
+}
+
+impl HandshakeError {
+    // Label of the error in the metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HandshakeError::Timeout => "timeout",
+            HandshakeError::BadFormat(_) => "bad_format",
+            HandshakeError::BadSignature => "bad_signature",
+            HandshakeError::CanisterNotAllowed(_) => "canister_not_allowed",
+            HandshakeError::UnknownClient(_) => "unknown_client",
+            HandshakeError::OpenRefused(_) => "open_refused",
+            HandshakeError::Transport(_) => "transport",
+        }
+    }
+
+    pub fn close_frame(&self) -> CloseFrame {
+        let (code, reason) = match self {
+            HandshakeError::Timeout => (
+                CloseCode::Library(CLOSE_HANDSHAKE_TIMEOUT),
+                "no first message".to_string(),
+            ),
+            HandshakeError::BadFormat(e) => (
+                CloseCode::Library(CLOSE_BAD_FORMAT),
+                format!("bad first message: {}", e),
+            ),
+            HandshakeError::BadSignature => (
+                CloseCode::Library(CLOSE_BAD_SIGNATURE),
+                "bad signature".to_string(),
+            ),
+            HandshakeError::CanisterNotAllowed(canister_id) => (
+                CloseCode::Library(CLOSE_CANISTER_NOT_ALLOWED),
+                format!("canister {} not allowed", canister_id),
+            ),
+            HandshakeError::UnknownClient(client_id) => (
+                CloseCode::Library(CLOSE_UNKNOWN_CLIENT),
+                format!("unknown client #{}", client_id),
+            ),
+            HandshakeError::OpenRefused(e) => (
+                CloseCode::Library(CLOSE_OPEN_REFUSED),
+                format!("canister refused the websocket: {:?}", e),
+            ),
+            HandshakeError::Transport(_) => (CloseCode::Error, "canister unavailable".to_string()),
+        };
+        CloseFrame { code, reason }
+    }
+}
+
+// The errors of the canister that have their own close code are reported with it.
+impl From<CallError> for HandshakeError {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::Canister(WsError::DecodeError(e)) => HandshakeError::BadFormat(e),
+            CallError::Canister(WsError::BadSignature) => HandshakeError::BadSignature,
+            CallError::Canister(WsError::UnknownClient(client_id)) => {
+                HandshakeError::UnknownClient(client_id)
+            }
+            CallError::Canister(e) => HandshakeError::OpenRefused(e),
+            CallError::Transport(e) => HandshakeError::Transport(e),
+        }
+    }
+}
+
+// Check the first message of a client before opening its websocket with the canister.
+// Returns the client and canister ids on success.
+pub async fn check(
+    agent: &Agent,
+    config: &Config,
+    m: &FirstMessageFromClient,
+) -> Result<(u64, Principal), HandshakeError> {
+    let content: FirstMessage =
+        decode(&m.client_canister_id).map_err(|e| HandshakeError::BadFormat(e.to_string()))?;
+    let canister_id = Principal::from_text(&content.canister_id)
+        .map_err(|e| HandshakeError::BadFormat(e.to_string()))?;
+    if !config.is_canister_allowed(&canister_id) {
+        return Err(HandshakeError::CanisterNotAllowed(canister_id));
+    }
+    let client_id = content.client_id;
+
+    let client_key = canister_methods::ws_get_client_key(agent, &canister_id, client_id).await?;
+    let sig = Signature::from_slice(&m.sig).map_err(|_| HandshakeError::BadSignature)?;
+    client_key
+        .verify(&m.client_canister_id, &sig)
+        .map_err(|_| HandshakeError::BadSignature)?;
+    Ok((client_id, canister_id))
+}
+
+// Open the websocket of a client whose first message passed the check.
+pub async fn open(
+    agent: &Agent,
+    canister_id: &Principal,
+    m: FirstMessageFromClient,
+) -> Result<(), HandshakeError> {
+    canister_methods::ws_open(agent, canister_id, m.client_canister_id, m.sig).await?;
+    Ok(())
+}
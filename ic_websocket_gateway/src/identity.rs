@@ -0,0 +1,41 @@
+use ic_agent::{export::Principal, identity::BasicIdentity, Identity};
+use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+// The canister keys the message queues by the principal of the gateway, so the gateway keeps
+// its identity across restarts in a PEM file holding the PKCS#8 encoded Ed25519 key.
+
+// Write a new key to path, which must not exist yet, and return the principal of the identity.
+pub fn generate_identity(path: &Path) -> Result<Principal, String> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| "could not generate a key pair".to_string())?;
+    let pem = pem::encode(&pem::Pem {
+        tag: "PRIVATE KEY".to_string(),
+        contents: pkcs8.as_ref().to_vec(),
+    });
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+
+    load_identity(path)?.sender()
+}
+
+pub fn load_identity(path: &Path) -> Result<BasicIdentity, String> {
+    BasicIdentity::from_pem_file(path)
+        .map_err(|e| format!("could not load the identity from {}: {}", path.display(), e))
+}
+
+// An identity that only lasts until the gateway stops, for local testing.
+pub fn ephemeral_identity() -> BasicIdentity {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .expect("Could not generate a key pair.");
+    BasicIdentity::from_key_pair(
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).expect("Could not read the key pair."),
+    )
+}
@@ -0,0 +1,136 @@
+use ezsockets::{CloseCode, CloseFrame};
+use std::time::Instant;
+
+use crate::metrics;
+
+// Close codes of the websockets closed for exceeding a limit.
+pub const CLOSE_TOO_MANY_CANISTER_SESSIONS: u16 = 4007;
+pub const CLOSE_MESSAGE_RATE_EXCEEDED: u16 = 4008;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Limit {
+    // Checked when a connection is accepted, before it is a websocket.
+    Connections,
+    ConnectionsPerIp,
+    SessionsPerCanister,
+    MessageRate,
+}
+
+impl Limit {
+    pub fn count(self) {
+        let label = match self {
+            Limit::Connections => "connections",
+            Limit::ConnectionsPerIp => "connections_per_ip",
+            Limit::SessionsPerCanister => "sessions_per_canister",
+            Limit::MessageRate => "message_rate",
+        };
+        metrics::LIMIT_HITS.with_label_values(&[label]).inc();
+    }
+
+    // Count the hit and return the frame to close the websocket with.
+    pub fn hit(self) -> CloseFrame {
+        self.count();
+        let (code, reason) = match self {
+            Limit::SessionsPerCanister => (
+                CLOSE_TOO_MANY_CANISTER_SESSIONS,
+                "too many websockets for this canister",
+            ),
+            Limit::MessageRate => (CLOSE_MESSAGE_RATE_EXCEEDED, "message rate exceeded"),
+            Limit::Connections | Limit::ConnectionsPerIp => {
+                unreachable!("connections are refused before they are websockets")
+            }
+        };
+        CloseFrame {
+            code: CloseCode::Library(code),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+// Allows bursts of up to capacity messages, refilled at rate messages per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, capacity: u32) -> Self {
+        Self::new_at(rate, capacity, Instant::now())
+    }
+
+    fn new_at(rate: u32, capacity: u32, now: Instant) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            rate: rate as f64,
+            tokens: capacity as f64,
+            refilled: now,
+        }
+    }
+
+    // Take a token for a message, returns false if none is left.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn burst_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(2, 5, start);
+        for _ in 0..5 {
+            assert!(bucket.try_take_at(start));
+        }
+        assert!(!bucket.try_take_at(start));
+    }
+
+    #[test]
+    fn refill_at_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(2, 5, start);
+        for _ in 0..5 {
+            assert!(bucket.try_take_at(start));
+        }
+        // Half a token after a quarter of a second, one after half a second.
+        assert!(!bucket.try_take_at(start + Duration::from_millis(250)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(500)));
+
+        let later = start + Duration::from_millis(1500);
+        assert!(bucket.try_take_at(later));
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+    }
+
+    #[test]
+    fn refill_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(2, 5, start);
+        assert!(bucket.try_take_at(start));
+
+        let later = start + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert!(bucket.try_take_at(later));
+        }
+        assert!(!bucket.try_take_at(later));
+    }
+}
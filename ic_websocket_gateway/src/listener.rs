@@ -0,0 +1,196 @@
+use enfync::{builtin::native::TokioHandle, TryAdopt};
+use ezsockets::{Request, Server, Socket, SocketConfig};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request as HandshakeRequest, Response},
+    Error as WsError,
+};
+use tracing::{debug, warn};
+
+use crate::{limits::Limit, GatewayServer};
+
+// The TCP connections from their accept until their websocket closes, including those still in
+// the TLS or websocket handshake.
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug)]
+pub struct Connections {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    counts: Mutex<Counts>,
+}
+
+impl Connections {
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>) -> Self {
+        Connections {
+            max_connections,
+            max_connections_per_ip,
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    // Count a new connection from ip, unless it exceeds a limit.
+    pub fn open(&self, ip: IpAddr) -> Result<(), Limit> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_connections.is_some_and(|max| counts.total >= max) {
+            return Err(Limit::Connections);
+        }
+        let ip_connections = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if self
+            .max_connections_per_ip
+            .is_some_and(|max| ip_connections >= max)
+        {
+            return Err(Limit::ConnectionsPerIp);
+        }
+        counts.total += 1;
+        counts.per_ip.insert(ip, ip_connections + 1);
+        Ok(())
+    }
+
+    pub fn close(&self, ip: IpAddr) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Entry::Occupied(mut connections) = counts.per_ip.entry(ip) {
+            *connections.get_mut() -= 1;
+            if *connections.get() == 0 {
+                connections.remove();
+            }
+            counts.total -= 1;
+        }
+    }
+}
+
+// Accept the connections until the gateway stops. Connections exceeding a limit are dropped
+// right away, the others are upgraded to websockets in tasks of their own and handed to the
+// server, which closes them with the connections when their websockets close.
+pub async fn run(
+    server: Server<GatewayServer>,
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    connections: Arc<Connections>,
+    handshake_timeout: Duration,
+) {
+    let handle =
+        TokioHandle::try_adopt().expect("The websocket server only runs in a tokio runtime.");
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Could not accept a connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(limit) = connections.open(address.ip()) {
+            warn!(
+                "Refusing connection from {}: {:?} limit reached.",
+                address, limit
+            );
+            limit.count();
+            continue;
+        }
+
+        let server = server.clone();
+        let tls = tls.clone();
+        let connections = Arc::clone(&connections);
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(handshake_timeout, upgrade(stream, tls, handle)).await {
+                Ok(Ok((socket, request))) => server.accept(socket, request, address),
+                Ok(Err(e)) => {
+                    debug!("Websocket handshake with {} failed: {}", address, e);
+                    connections.close(address.ip());
+                }
+                Err(_) => {
+                    debug!("Websocket handshake with {} timed out.", address);
+                    connections.close(address.ip());
+                }
+            }
+        });
+    }
+}
+
+// Run the TLS handshake if configured, and then the websocket handshake.
+async fn upgrade(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    handle: TokioHandle,
+) -> Result<(Socket, Request), WsError> {
+    match tls {
+        Some(tls) => websocket_handshake(tls.accept(stream).await?, handle).await,
+        None => websocket_handshake(stream, handle).await,
+    }
+}
+
+async fn websocket_handshake<S>(
+    stream: S,
+    handle: TokioHandle,
+) -> Result<(Socket, Request), WsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The request is kept for the server, which gets it along with the websocket.
+    let mut request = None;
+    // The signature is the one tungstenite expects.
+    #[allow(clippy::result_large_err)]
+    let callback =
+        |req: &HandshakeRequest, response: Response| -> Result<Response, ErrorResponse> {
+            let mut builder = Request::builder()
+                .method(req.method().clone())
+                .uri(req.uri().clone())
+                .version(req.version());
+            for (name, value) in req.headers() {
+                builder = builder.header(name, value);
+            }
+            request = Some(builder.body(()).map_err(|_| ErrorResponse::default())?);
+            Ok(response)
+        };
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+    let socket = Socket::new(websocket, SocketConfig::default(), handle);
+    // The callback ran if the handshake succeeded.
+    Ok((socket, request.unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_limited_per_ip() {
+        let connections = Connections::new(Some(3), Some(2));
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(connections.open(first).is_ok());
+        assert!(connections.open(first).is_ok());
+        assert!(matches!(
+            connections.open(first),
+            Err(Limit::ConnectionsPerIp)
+        ));
+        assert!(connections.open(second).is_ok());
+        assert!(matches!(connections.open(second), Err(Limit::Connections)));
+
+        // Closed connections free their slots.
+        connections.close(first);
+        assert!(connections.open(second).is_ok());
+        assert!(matches!(connections.open(second), Err(Limit::Connections)));
+        connections.close(second);
+        connections.close(second);
+        assert!(connections.open(first).is_ok());
+        assert!(matches!(
+            connections.open(first),
+            Err(Limit::ConnectionsPerIp)
+        ));
+    }
+}
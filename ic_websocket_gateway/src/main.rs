@@ -1,45 +1,65 @@
 use async_trait::async_trait;
-use candid::CandidType;
-use ed25519_compact::Signature;
-use ezsockets::{Error, Server, Socket};
-use ic_agent::{export::Principal, identity::BasicIdentity, Agent};
-use serde::{Deserialize, Serialize};
-use serde_cbor::{from_slice, to_vec};
+use canister_methods::CallError;
+use canister_poller::{CanisterPoller, PollerHealth};
+use clap::Parser;
+use config::{Cli, Command, Config, LogFormat};
+use ezsockets::{CloseCode, CloseFrame, Error, Request, Server, Socket};
+use handshake::HandshakeError;
+use ic_agent::{export::Principal, identity::BasicIdentity, Agent, Identity};
+use ic_websocket_protocol::{
+    decode, decode_json, encode, ClientMessage, FirstMessage, FirstMessageFromClient,
+    JsonClientMessage, JsonFirstMessageFromClient, WsError,
+};
+use limits::{Limit, TokenBucket};
+use listener::Connections;
+use relay::Relay;
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::Duration,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
 };
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+use tracing::{debug, error, field, field::display, info, info_span, warn, Instrument, Span};
 
 mod canister_methods;
+mod canister_poller;
+mod config;
+mod handshake;
+mod identity;
+mod limits;
+mod listener;
+mod metrics;
+mod relay;
+mod tls;
 
 type SessionID = u64;
-type Session = ezsockets::Session<SessionID, ()>;
-
-// url for local testing
-// for local testing also the agent needs to fetch the root key
-const URL: &str = "http://127.0.0.1:4943";
-const FETCH_KEY: bool = true;
-
-// url for mainnet
-// const URL: &str = "https://ic0.app";
-// const FETCH_KEY: bool = false;
-
-#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
-#[candid_path("ic_cdk::export::candid")]
-struct FirstMessageFromClient {
-    #[serde(with = "serde_bytes")]
-    client_canister_id: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    sig: Vec<u8>,
+type Session = ezsockets::Session<SessionID, SessionCall>;
+
+// Clients choose the encoding of the messages with the frame type of their first message:
+// binary frames for CBOR, text frames for JSON.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Cbor,
+    Json,
 }
 
-#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
-#[candid_path("ic_cdk::export::candid")]
-struct ClientCanisterId {
-    client_id: u64,
-    canister_id: String,
+// A websocket starts in the handshake until the client's first message opened it with the canister.
+#[derive(Debug)]
+enum SessionState {
+    Handshake,
+    Open,
+    // Rejected during the handshake, waiting for the close frame to go out.
+    Closed,
+}
+
+#[derive(Debug)]
+enum SessionCall {
+    // Sent when the time for the first message is up.
+    HandshakeDeadline,
+    // Sent when the gateway shuts down.
+    Shutdown,
+    // The reply of the canister to a message from the client, in the order of the messages.
+    Relayed(Result<(), CallError>),
 }
 
 #[derive(Debug)]
@@ -48,156 +68,244 @@ struct GatewaySession {
     handle: Session,
     server_handle: Server<GatewayServer>,
     agent: Agent,
+    config: Arc<Config>,
+    state: SessionState,
+    // Passes on the messages of the client once the websocket is open.
+    relay: Option<Relay>,
+    // Limits the rate of the messages from the client, if configured.
+    message_rate: Option<TokenBucket>,
+    // Carries the session id, and the client and canister ids once the websocket is open.
+    span: Span,
+}
+
+impl GatewaySession {
+    fn reject(&mut self, e: HandshakeError) {
+        warn!("Rejecting websocket: {}", e);
+        metrics::HANDSHAKE_FAILURES
+            .with_label_values(&[e.reason()])
+            .inc();
+        self.state = SessionState::Closed;
+        // The session is gone already if the client closed the websocket itself.
+        let _ = self.handle.close(Some(e.close_frame()));
+    }
+
+    async fn open(
+        &mut self,
+        first_message: Result<FirstMessageFromClient, HandshakeError>,
+        encoding: Encoding,
+    ) {
+        let (m, client_id, canister_id) = match first_message {
+            Ok(m) => match handshake::check(&self.agent, &self.config, &m).await {
+                Ok((client_id, canister_id)) => (m, client_id, canister_id),
+                Err(e) => return self.reject(e),
+            },
+            Err(e) => return self.reject(e),
+        };
+
+        self.span.record("client_id", client_id);
+        self.span.record("canister_id", display(canister_id));
+        // The websocket counts towards the limit of the canister and gets the messages of the
+        // canister before it is opened with it, the canister may send messages as soon as it is.
+        let reserved = self
+            .server_handle
+            .call_with(|reserved| ServerCall::ReserveSession {
+                session: PendingSession {
+                    session_id: self.id,
+                    session: self.handle.clone(),
+                    encoding,
+                    canister_id: canister_id.to_string(),
+                    canister_client_id: client_id,
+                    span: self.span.clone(),
+                },
+                reserved,
+            })
+            .await;
+        match reserved {
+            Some(Ok(())) => {}
+            Some(Err(frame)) => {
+                self.state = SessionState::Closed;
+                let _ = self.handle.close(Some(frame));
+                return;
+            }
+            // The gateway stopped.
+            None => return,
+        }
+
+        match handshake::open(&self.agent, &canister_id, m).await {
+            Ok(()) => {
+                info!("Websocket opened.");
+                self.state = SessionState::Open;
+                self.relay = Some(Relay::start(
+                    self.agent.clone(),
+                    canister_id,
+                    self.config.max_in_flight_messages,
+                    self.handle.clone(),
+                ));
+                let _ = self
+                    .server_handle
+                    .call(ServerCall::ConnectCanister(self.id));
+            }
+            Err(e) => {
+                let _ = self.server_handle.call(ServerCall::ReleaseSession(self.id));
+                self.reject(e);
+            }
+        }
+    }
+
+    // Pass on a message from the client, encoded as a ClientMessage in CBOR.
+    async fn relay(&self, msg: Vec<u8>) {
+        debug!("Message from the client.");
+        if let Some(relay) = &self.relay {
+            relay.send(msg).await;
+        }
+    }
+
+    // Close the websocket if the client sends messages faster than allowed.
+    fn within_message_rate(&mut self) -> bool {
+        if let SessionState::Closed = self.state {
+            return false;
+        }
+        if let Some(bucket) = &mut self.message_rate {
+            if !bucket.try_take() {
+                warn!("Closing websocket: message rate exceeded.");
+                self.state = SessionState::Closed;
+                let _ = self.handle.close(Some(Limit::MessageRate.hit()));
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn receive_text(&mut self, text: String) {
+        if !self.within_message_rate() {
+            return;
+        }
+        match self.state {
+            SessionState::Handshake => {
+                let first_message = decode_json::<JsonFirstMessageFromClient>(&text)
+                    .map(FirstMessageFromClient::from)
+                    .map_err(|e| HandshakeError::BadFormat(e.to_string()));
+                self.open(first_message, Encoding::Json).await;
+            }
+            SessionState::Open => match decode_json::<JsonClientMessage>(&text) {
+                Ok(m) => {
+                    let msg = encode(&ClientMessage::from(m));
+                    self.relay(msg).await;
+                }
+                Err(e) => warn!("Dropping message: {}", e),
+            },
+            SessionState::Closed => {}
+        }
+    }
+
+    async fn receive_binary(&mut self, bytes: Vec<u8>) {
+        if !self.within_message_rate() {
+            return;
+        }
+        match self.state {
+            SessionState::Handshake => {
+                let first_message =
+                    decode(&bytes).map_err(|e| HandshakeError::BadFormat(e.to_string()));
+                self.open(first_message, Encoding::Cbor).await;
+            }
+            SessionState::Open => self.relay(bytes).await,
+            SessionState::Closed => {}
+        }
+    }
 
-    canister_connected: bool,
-    client_id: Option<u64>,
-    canister_id: Option<Principal>,
+    async fn receive_call(&mut self, call: SessionCall) {
+        match call {
+            SessionCall::HandshakeDeadline => {
+                if let SessionState::Handshake = self.state {
+                    self.reject(HandshakeError::Timeout);
+                }
+            }
+            SessionCall::Shutdown => {
+                self.state = SessionState::Closed;
+                // Pass on the messages already received before closing.
+                if let Some(relay) = self.relay.take() {
+                    relay.flush().await;
+                }
+                let _ = self.handle.close(Some(shutting_down()));
+            }
+            SessionCall::Relayed(Ok(())) => metrics::MESSAGES
+                .with_label_values(&[metrics::TO_CANISTER])
+                .inc(),
+            SessionCall::Relayed(Err(e)) => {
+                warn!("Closing websocket: ws_message failed: {}", e);
+                if let SessionState::Closed = self.state {
+                    return;
+                }
+                self.state = SessionState::Closed;
+                self.relay = None;
+                let _ = self.handle.close(Some(relay::relay_failed()));
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl ezsockets::SessionExt for GatewaySession {
     type ID = SessionID;
-    type Args = ();
-    type Params = ();
+    type Call = SessionCall;
 
     fn id(&self) -> &Self::ID {
         &self.id
     }
 
-    async fn text(&mut self, _text: String) -> Result<(), Error> {
-        unimplemented!()
+    async fn on_text(&mut self, text: String) -> Result<(), Error> {
+        let span = self.span.clone();
+        self.receive_text(text).instrument(span).await;
+        Ok(())
     }
 
-    async fn binary(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
-        if !self.canister_connected {
-            let m: FirstMessageFromClient = from_slice(&bytes).unwrap();
-            let content: ClientCanisterId = from_slice(&m.client_canister_id).unwrap();
-            let canister_id = Principal::from_text(&content.canister_id).unwrap();
-
-            let client_key =
-                canister_methods::ws_get_client_key(&self.agent, &canister_id, content.client_id)
-                    .await;
-            let sig = Signature::from_slice(&m.sig).unwrap();
-            let valid = client_key.verify(&m.client_canister_id, &sig);
-
-            match valid {
-                Ok(_) => {
-                    self.canister_connected = true;
-                    self.client_id = Some(content.client_id);
-                    self.canister_id = Some(canister_id);
-
-                    self.server_handle.call(ConnectCanister {
-                        session_id: self.id,
-                        session: self.handle.clone(),
-                        canister_id: content.canister_id,
-                        canister_client_id: content.client_id,
-                    });
-                    let ret = canister_methods::ws_open(
-                        &self.agent,
-                        &canister_id,
-                        m.client_canister_id,
-                        m.sig,
-                    )
-                    .await;
-                    println!("ws_open:{}", ret);
-                }
-                Err(_) => println!("Client's signature does not verify."),
-            }
-        } else {
-            println!("Message from client #{}", self.client_id.unwrap());
-            canister_methods::ws_message(&self.agent, &self.canister_id.unwrap(), bytes).await;
-        }
+    async fn on_binary(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        let span = self.span.clone();
+        self.receive_binary(bytes).instrument(span).await;
         Ok(())
     }
 
-    async fn call(&mut self, params: ()) -> Result<(), Error> {
-        let () = params;
+    async fn on_call(&mut self, call: SessionCall) -> Result<(), Error> {
+        let span = self.span.clone();
+        self.receive_call(call).instrument(span).await;
         Ok(())
     }
 }
 
+// A websocket being opened with a canister.
 #[derive(Debug)]
-struct ConnectCanister {
+struct PendingSession {
     session_id: u64,
     session: Session,
+    encoding: Encoding,
     canister_id: String,
     canister_client_id: u64,
+    span: Span,
 }
 
 #[derive(Debug)]
-struct CanisterPoller {
-    canister_id: String,
-    canister_client_session_map: Arc<Mutex<HashMap<u64, Session>>>,
-    identity: Arc<BasicIdentity>,
-}
-
-#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq)]
-pub struct CertMessage {
-    pub key: String,
-    #[serde(with = "serde_bytes")]
-    pub val: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    pub cert: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    pub tree: Vec<u8>,
+enum ServerCall {
+    // Connect a websocket to the poller of its canister before opening it with the canister.
+    // Answers with the frame to close the websocket with if it cannot be opened.
+    ReserveSession {
+        session: PendingSession,
+        reserved: oneshot::Sender<Result<(), CloseFrame>>,
+    },
+    // The canister refused to open a websocket connected with ReserveSession.
+    ReleaseSession(SessionID),
+    // The canister opened a websocket connected with ReserveSession.
+    ConnectCanister(SessionID),
+    // Close all websockets and answer once ws_close was called for all their clients.
+    Shutdown(oneshot::Sender<()>),
+    // Sent when half of the shutdown timeout is up, to stop waiting for the websockets to close.
+    CloseRemaining,
 }
 
-impl CanisterPoller {
-    async fn run_polling(&self) {
-        println!("Start of polling.");
-        let can_map = Arc::clone(&self.canister_client_session_map);
-        let agent = canister_methods::get_new_agent(URL, self.identity.clone(), FETCH_KEY).await;
-        let canister_id = Principal::from_text(&self.canister_id).unwrap();
-        tokio::spawn({
-            let interval = Duration::from_millis(200);
-            let mut nonce: u64 = 0;
-            async move {
-                loop {
-                    let msgs = canister_methods::ws_get_messages(&agent, &canister_id, nonce).await;
-
-                    for encoded_message in msgs.messages {
-                        let client_id = encoded_message.client_id;
-
-                        println!(
-                            "Message to client #{} with key {}.",
-                            client_id, encoded_message.key
-                        );
-
-                        let map = can_map.lock().unwrap();
-                        let s = map.get(&client_id).unwrap();
-
-                        let m = CertMessage {
-                            key: encoded_message.key.clone(),
-                            val: encoded_message.val,
-                            cert: msgs.cert.clone(),
-                            tree: msgs.tree.clone(),
-                        };
-
-                        if s.alive() {
-                            s.binary(to_vec(&m).unwrap());
-                        }
-
-                        nonce = encoded_message
-                            .key
-                            .split('_')
-                            .last()
-                            .unwrap()
-                            .parse()
-                            .unwrap();
-                        nonce += 1
-                    }
-
-                    tokio::time::sleep(interval).await;
-                }
-            }
-        });
-    }
-
-    fn add_session(&self, canister_client_id: u64, session: Session) {
-        let map = &self.canister_client_session_map;
-        let mut m = map.lock().unwrap();
-        m.insert(canister_client_id, session);
-    }
+#[derive(Debug)]
+struct Shutdown {
+    done: Option<oneshot::Sender<()>>,
+    // The ws_close calls of the clients disconnected since the shutdown started.
+    closing: Vec<JoinHandle<()>>,
 }
 
 #[derive(Debug)]
@@ -205,112 +313,452 @@ struct GatewayServer {
     next_session_id: u64,
     handle: Server<Self>,
     connected_canisters: HashMap<String, CanisterPoller>,
-    identity: Arc<BasicIdentity>,
-    close_args: HashMap<SessionID, ClientCanisterId>,
+    close_args: HashMap<SessionID, FirstMessage>,
     agent: Agent,
+    config: Arc<Config>,
+    // All open websockets, including those still in the handshake, with the address of the client.
+    sessions: HashMap<SessionID, (Session, IpAddr)>,
+    // Counted by the listener when it accepts a connection, until its websocket closes.
+    connections: Arc<Connections>,
+    // Websockets connected to the poller of their canister while they are opened with it.
+    pending_sessions: HashMap<SessionID, PendingSession>,
+    shutdown: Option<Shutdown>,
 }
 
 #[async_trait]
 impl ezsockets::ServerExt for GatewayServer {
-    type Params = ConnectCanister;
+    type Call = ServerCall;
     type Session = GatewaySession;
 
-    async fn accept(
+    async fn on_connect(
         &mut self,
         socket: Socket,
-        _address: SocketAddr,
-        _args: (),
-    ) -> Result<Session, Error> {
+        _request: Request,
+        address: SocketAddr,
+    ) -> Result<Session, Option<CloseFrame>> {
+        // The connection limits were checked when the listener accepted the connection.
+        if self.shutdown.is_some() {
+            self.connections.close(address.ip());
+            return Err(Some(shutting_down()));
+        }
+        metrics::OPEN_SESSIONS.inc();
+
         let id = self.next_session_id;
         self.next_session_id += 1;
-        println!("Client connected.");
-        let agent = canister_methods::get_new_agent(URL, self.identity.clone(), FETCH_KEY).await;
-
+        let span = info_span!(
+            "session",
+            session_id = id,
+            client_id = field::Empty,
+            canister_id = field::Empty
+        );
+        span.in_scope(|| info!("Client connected from {}.", address));
         let session = Session::create(
             |handle| GatewaySession {
                 id,
                 handle,
                 server_handle: self.handle.clone(),
-                agent,
-
-                canister_connected: false,
-                client_id: None,
-                canister_id: None,
+                agent: self.agent.clone(),
+                config: self.config.clone(),
+                state: SessionState::Handshake,
+                relay: None,
+                message_rate: self
+                    .config
+                    .message_rate
+                    .map(|(rate, burst)| TokenBucket::new(rate, burst)),
+                span,
             },
             id,
             socket,
         );
 
+        // Clients that do not send their first message in time are disconnected.
+        let deadline = session.clone();
+        let handshake_timeout = self.config.handshake_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(handshake_timeout).await;
+            let _ = deadline.call(SessionCall::HandshakeDeadline);
+        });
+
+        self.sessions.insert(id, (session.clone(), address.ip()));
         Ok(session)
     }
 
-    async fn disconnected(
+    async fn on_disconnect(
         &mut self,
         id: <Self::Session as ezsockets::SessionExt>::ID,
+        _reason: Result<Option<CloseFrame>, Error>,
     ) -> Result<(), Error> {
-        let close_args = self.close_args.remove(&id).unwrap();
-        println!("Websocket with client #{} closed.", close_args.client_id);
-        let canister_id = Principal::from_text(&close_args.canister_id).unwrap();
-        canister_methods::ws_close(&self.agent, &canister_id, close_args.client_id).await;
+        if let Some((_, ip)) = self.sessions.remove(&id) {
+            self.connections.close(ip);
+        }
+        metrics::OPEN_SESSIONS.dec();
+        // Websockets that closed before the client was connected to a canister have nothing to close.
+        if let Some(close_args) = self.close_args.remove(&id) {
+            let span = info_span!(
+                "session",
+                session_id = id,
+                client_id = close_args.client_id,
+                canister_id = %close_args.canister_id
+            );
+            span.in_scope(|| self.disconnect(close_args));
+        }
+        if self.sessions.is_empty() {
+            self.finish_shutdown();
+        }
         Ok(())
     }
 
-    async fn call(&mut self, add_canister: Self::Params) -> Result<(), Error> {
-        let canister_id = add_canister.canister_id;
-        let session = add_canister.session;
-        let canister_client_id = add_canister.canister_client_id;
+    async fn on_call(&mut self, call: Self::Call) -> Result<(), Error> {
+        match call {
+            ServerCall::ReserveSession { session, reserved } => {
+                let span = session.span.clone();
+                let _ = reserved.send(span.in_scope(|| self.reserve_session(session)));
+            }
+            ServerCall::ReleaseSession(session_id) => self.release_session(session_id),
+            ServerCall::ConnectCanister(session_id) => self.connect_canister(session_id),
+            ServerCall::Shutdown(done) => self.start_shutdown(done),
+            ServerCall::CloseRemaining => {
+                if self.shutdown.is_some() && !self.close_args.is_empty() {
+                    warn!(
+                        "{} websockets did not close in time, closing them with the canisters.",
+                        self.close_args.len()
+                    );
+                    for (id, close_args) in std::mem::take(&mut self.close_args) {
+                        let span = info_span!(
+                            "session",
+                            session_id = id,
+                            client_id = close_args.client_id,
+                            canister_id = %close_args.canister_id
+                        );
+                        span.in_scope(|| self.disconnect(close_args));
+                    }
+                }
+                self.finish_shutdown();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GatewayServer {
+    fn disconnect(&mut self, close_args: FirstMessage) {
+        info!("Websocket closed.");
+        metrics::CANISTER_SESSIONS
+            .with_label_values(&[&close_args.canister_id])
+            .dec();
+        self.remove_session(&close_args.canister_id, close_args.client_id);
+        self.close_client(&close_args.canister_id, close_args.client_id);
+    }
+
+    // Polling stops with the last client of the canister and resumes with the next one.
+    fn remove_session(&mut self, canister_id: &str, client_id: u64) {
+        if let Some(poller) = self.connected_canisters.get_mut(canister_id) {
+            if !poller.remove_session(client_id) {
+                poller.stop_polling();
+                let _ = metrics::CANISTER_SESSIONS.remove_label_values(&[canister_id]);
+            }
+        }
+    }
+
+    // Close the client with the canister in a task of its own, so that the server goes on with the
+    // other websockets meanwhile. While shutting down, the shutdown waits for the call.
+    fn close_client(&mut self, canister_id: &str, client_id: u64) {
+        let canister_id = Principal::from_text(canister_id).unwrap();
+        let agent = self.agent.clone();
+        let close = tokio::spawn(
+            async move {
+                if let Err(e) = canister_methods::ws_close(&agent, &canister_id, client_id).await {
+                    warn!("ws_close failed: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
+        if let Some(shutdown) = &mut self.shutdown {
+            shutdown.closing.push(close);
+        }
+    }
+
+    // Stop polling and close all websockets. Each session closes its websocket once the canister replied
+    // to the messages it received, and the clients are closed with the canisters as the websockets close.
+    fn start_shutdown(&mut self, done: oneshot::Sender<()>) {
+        info!("Closing {} websockets.", self.sessions.len());
+        for poller in self.connected_canisters.values_mut() {
+            poller.stop_polling();
+        }
+        self.shutdown = Some(Shutdown {
+            done: Some(done),
+            closing: Vec::new(),
+        });
+        for (session, _) in self.sessions.values() {
+            let _ = session.call(SessionCall::Shutdown);
+        }
+
+        // Clients whose websockets do not close in time are closed with the canisters anyway.
+        let handle = self.handle.clone();
+        let timeout = self.config.shutdown_timeout / 2;
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let _ = handle.call(ServerCall::CloseRemaining);
+        });
+
+        if self.sessions.is_empty() {
+            self.finish_shutdown();
+        }
+    }
+
+    // Answer the shutdown once the pending ws_close calls are done.
+    fn finish_shutdown(&mut self) {
+        let shutdown = match &mut self.shutdown {
+            Some(shutdown) => shutdown,
+            None => return,
+        };
+        if let Some(done) = shutdown.done.take() {
+            let closing = std::mem::take(&mut shutdown.closing);
+            tokio::spawn(async move {
+                for close in closing {
+                    let _ = close.await;
+                }
+                let _ = done.send(());
+            });
+        }
+    }
+
+    // Connect the websocket to the poller of its canister, unless the websockets open and being
+    // opened with the canister reached the limit.
+    fn reserve_session(&mut self, pending: PendingSession) -> Result<(), CloseFrame> {
+        if self.shutdown.is_some() {
+            return Err(shutting_down());
+        }
+        let poller = self
+            .connected_canisters
+            .entry(pending.canister_id.clone())
+            .or_insert_with(|| {
+                CanisterPoller::new(
+                    pending.canister_id.clone(),
+                    self.agent.clone(),
+                    self.config.clone(),
+                )
+            });
+        if let Some(max_sessions) = self.config.max_sessions_per_canister {
+            if poller.session_count() >= max_sessions {
+                warn!(
+                    "Closing websocket: {} websockets are open with the canister.",
+                    poller.session_count()
+                );
+                return Err(Limit::SessionsPerCanister.hit());
+            }
+        }
+        // The canister opens the websocket of a client only once.
+        if poller.has_session(pending.canister_client_id) {
+            let e = HandshakeError::OpenRefused(WsError::Unauthorized);
+            warn!("Rejecting websocket: the client has another websocket.");
+            metrics::HANDSHAKE_FAILURES
+                .with_label_values(&[e.reason()])
+                .inc();
+            return Err(e.close_frame());
+        }
+
+        poller.add_session(
+            pending.canister_client_id,
+            pending.session.clone(),
+            pending.encoding,
+        );
+        if !poller.is_polling() {
+            poller.start_polling();
+        } else if let PollerHealth::Unhealthy {
+            failures,
+            last_error,
+        } = poller.health()
+        {
+            warn!(
+                "Connected to a canister that failed the last {} polls: {}",
+                failures, last_error
+            );
+        }
+        self.pending_sessions.insert(pending.session_id, pending);
+        Ok(())
+    }
+
+    fn release_session(&mut self, session_id: SessionID) {
+        if let Some(pending) = self.pending_sessions.remove(&session_id) {
+            pending
+                .span
+                .in_scope(|| self.remove_session(&pending.canister_id, pending.canister_client_id));
+        }
+    }
+
+    fn connect_canister(&mut self, session_id: SessionID) {
+        let pending = match self.pending_sessions.remove(&session_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let _span = pending.span.enter();
+        let canister_id = pending.canister_id;
+        let canister_client_id = pending.canister_client_id;
+
+        // The client may have disconnected while the canister opened its websocket, on_disconnect
+        // had nothing to close then.
+        if !self.sessions.contains_key(&session_id) {
+            info!("Websocket closed during the handshake.");
+            self.remove_session(&canister_id, canister_client_id);
+            self.close_client(&canister_id, canister_client_id);
+            return;
+        }
 
         self.close_args.insert(
-            add_canister.session_id,
-            ClientCanisterId {
+            session_id,
+            FirstMessage {
                 client_id: canister_client_id,
                 canister_id: canister_id.clone(),
             },
         );
+        metrics::CANISTER_SESSIONS
+            .with_label_values(&[&canister_id])
+            .inc();
+    }
+}
 
-        match self.connected_canisters.get_mut(&canister_id) {
-            None => {
-                let identity = self.identity.clone();
-                let poller = CanisterPoller {
-                    canister_id: canister_id.clone(),
-                    canister_client_session_map: Arc::new(Mutex::new(HashMap::new())),
-                    identity,
-                };
-                poller.add_session(canister_client_id, session);
-                poller.run_polling().await;
-                self.connected_canisters.insert(canister_id, poller);
-            }
-            Some(poller) => {
-                poller.add_session(canister_client_id, session);
+fn shutting_down() -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::Away,
+        reason: "gateway shutting down".to_string(),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Some(Command::GenerateIdentity { path }) = &cli.command {
+        match identity::generate_identity(path) {
+            Ok(principal) => println!("{}", principal),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
             }
         }
+        return;
+    }
 
-        Ok(())
+    let config = match Config::from_cli(cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let subscriber = tracing_subscriber::fmt().with_max_level(config.log_level);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
     }
+
+    let identity = match &config.identity_file {
+        Some(path) => match identity::load_identity(path) {
+            Ok(identity) => identity,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            warn!("No identity file given, the gateway principal changes on every start.");
+            identity::ephemeral_identity()
+        }
+    };
+    info!("Gateway principal: {}", identity.sender().unwrap());
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .enable_all()
+        .build()
+        .expect("Could not start the runtime.")
+        .block_on(run(config, identity));
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
-async fn main() {
-    let rng = ring::rand::SystemRandom::new();
-    let key_pair = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng)
-        .expect("Could not generate a key pair.");
-    let identity = BasicIdentity::from_key_pair(
-        ring::signature::Ed25519KeyPair::from_pkcs8(key_pair.as_ref())
-            .expect("Could not read the key pair."),
-    );
-    let identity = Arc::new(identity);
-    let agent = canister_methods::get_new_agent(URL, identity.clone(), FETCH_KEY).await;
-    agent.fetch_root_key().await.unwrap();
+async fn run(config: Config, identity: BasicIdentity) {
+    let config = Arc::new(config);
+    // All calls to the canisters go through this agent.
+    let agent =
+        match canister_methods::create_agent(&config.url, identity, config.fetch_root_key).await {
+            Ok(agent) => agent,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+    if let Some(metrics_address) = config.metrics_address {
+        tokio::spawn(metrics::serve(metrics_address));
+    }
 
+    let listen_address = config.listen_address;
+    let shutdown_timeout = config.shutdown_timeout;
+    let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+    let handshake_timeout = config.handshake_timeout;
+    let tls = match &config.tls {
+        Some((cert_file, key_file)) => match tls::acceptor(cert_file.clone(), key_file.clone()) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let listener = match TcpListener::bind(listen_address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen on {}: {}", listen_address, e);
+            std::process::exit(1);
+        }
+    };
+    let connections = Arc::new(Connections::new(
+        config.max_connections,
+        config.max_connections_per_ip,
+    ));
     let (server, _) = Server::create(|handle| GatewayServer {
         next_session_id: 0,
         handle,
         connected_canisters: HashMap::new(),
-        identity,
         close_args: HashMap::new(),
         agent,
+        config,
+        sessions: HashMap::new(),
+        connections: connections.clone(),
+        pending_sessions: HashMap::new(),
+        shutdown: None,
     });
-    ezsockets::tungstenite::run(server, "127.0.0.1:8080", |_| async move { Ok(()) })
-        .await
-        .unwrap();
+    info!("Listening on {}://{}.", scheme, listen_address);
+    tokio::select! {
+        _ = listener::run(server.clone(), listener, tls, connections, handshake_timeout) => {}
+        _ = shutdown_signal() => {}
+    }
+
+    // No more websockets are accepted from here on.
+    info!(
+        "Shutting down, waiting up to {:?} for the websockets to close.",
+        shutdown_timeout
+    );
+    let (done, closed) = oneshot::channel();
+    let _ = server.call(ServerCall::Shutdown(done));
+    match tokio::time::timeout(shutdown_timeout, closed).await {
+        Ok(_) => info!("All websockets closed."),
+        Err(_) => {
+            warn!("Shutdown timeout reached before all clients were closed with the canisters.")
+        }
+    }
+}
+
+// Resolves on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Could not listen for SIGTERM.");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,147 @@
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::{convert::Infallible, net::SocketAddr, time::Instant};
+use tracing::{error, info};
+
+// Metrics of the gateway, in the default prometheus registry and served in the text format on /metrics.
+
+pub static OPEN_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "ic_ws_gateway_open_sessions",
+        "Open websockets, including those still in the handshake."
+    )
+    .unwrap()
+});
+
+pub static CANISTER_SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "ic_ws_gateway_canister_sessions",
+        "Websockets opened with each canister.",
+        &["canister_id"]
+    )
+    .unwrap()
+});
+
+pub static HANDSHAKE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ic_ws_gateway_handshake_failures_total",
+        "Websockets rejected during the handshake, by reason.",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ic_ws_gateway_messages_total",
+        "Messages relayed, to_canister from the clients or to_client from the canisters.",
+        &["direction"]
+    )
+    .unwrap()
+});
+
+pub static CALL_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "ic_ws_gateway_call_duration_seconds",
+        "Duration of the calls to the canisters, by method.",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static LIMIT_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ic_ws_gateway_limit_hits_total",
+        "Connections refused or websockets closed for exceeding a limit, by limit.",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+pub static POLLING_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ic_ws_gateway_polling_errors_total",
+        "Failed polls of each canister.",
+        &["canister_id"]
+    )
+    .unwrap()
+});
+
+pub static NONCE_LAG: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "ic_ws_gateway_nonce_lag",
+        "Messages each canister holds for the gateway beyond the nonce it polled up to.",
+        &["canister_id"]
+    )
+    .unwrap()
+});
+
+pub const TO_CANISTER: &str = "to_canister";
+pub const TO_CLIENT: &str = "to_client";
+
+// Record the duration of a call to a canister method started at start.
+pub fn observe_call(method: &str, start: Instant) {
+    CALL_DURATION
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+fn render() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+    }
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => render(),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+// Serve the metrics until the gateway stops.
+pub async fn serve(address: SocketAddr) {
+    // The metrics are registered when first used, register them all so that they are exported from the start.
+    Lazy::force(&OPEN_SESSIONS);
+    Lazy::force(&CANISTER_SESSIONS);
+    Lazy::force(&HANDSHAKE_FAILURES);
+    Lazy::force(&MESSAGES);
+    Lazy::force(&CALL_DURATION);
+    Lazy::force(&LIMIT_HITS);
+    Lazy::force(&POLLING_ERRORS);
+    Lazy::force(&NONCE_LAG);
+
+    let server = match hyper::Server::try_bind(&address) {
+        Ok(builder) => builder.serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(handle))
+        })),
+        Err(e) => {
+            error!("Could not serve metrics on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics.", address);
+    if let Err(e) = server.await {
+        error!("Metrics server failed: {}", e);
+    }
+}
@@ -0,0 +1,240 @@
+use ezsockets::{CloseCode, CloseFrame};
+use ic_agent::{export::Principal, Agent};
+use ic_websocket_protocol::WsError;
+use std::{collections::VecDeque, future::Future, time::Instant};
+use tokio::{
+    sync::mpsc,
+    task::{JoinError, JoinHandle},
+};
+use tracing::{debug, Instrument};
+
+use crate::{
+    canister_methods::{self, CallError},
+    Session, SessionCall,
+};
+
+// Close code of the websockets whose messages could not be passed on to the canister.
+pub const CLOSE_RELAY_FAILED: u16 = 4009;
+
+pub fn relay_failed() -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::Library(CLOSE_RELAY_FAILED),
+        reason: "message not relayed".to_string(),
+    }
+}
+
+// The reply of the canister to a submitted message.
+type Reply = JoinHandle<Result<(), CallError>>;
+
+// Passes on the messages of a client to the canister. The messages are submitted one after the
+// other in the order the client sent them, but without waiting for the reply to a message before
+// submitting the next one. At most window messages wait for their replies, further messages wait
+// in the queue and then in the session.
+// The canister may still execute a message before the ones submitted earlier, and refuses it then
+// because of its sequence number. Such a message is submitted again once the canister replied to
+// the earlier ones. Any other failure ends the relay, as the canister refuses all later messages.
+#[derive(Debug)]
+pub struct Relay {
+    messages: mpsc::Sender<Vec<u8>>,
+    task: JoinHandle<()>,
+}
+
+impl Relay {
+    pub fn start(agent: Agent, canister_id: Principal, window: usize, session: Session) -> Self {
+        let (messages, queue) = mpsc::channel(window);
+        let submit = move |msg| submit(agent.clone(), canister_id, msg);
+        // The session is gone if the client disconnected, the replies are not needed anymore then.
+        let report = move |result| {
+            let _ = session.call(SessionCall::Relayed(result));
+        };
+        let task = tokio::spawn(relay_messages(submit, window, report, queue).in_current_span());
+        Relay { messages, task }
+    }
+
+    // Queue a message of the client, waiting while the queue is full.
+    pub async fn send(&self, msg: Vec<u8>) {
+        let _ = self.messages.send(msg).await;
+    }
+
+    // Wait until the canister replied to all queued messages.
+    pub async fn flush(self) {
+        drop(self.messages);
+        let _ = self.task.await;
+    }
+}
+
+// Submit a message, the reply is awaited in a task of its own.
+async fn submit(agent: Agent, canister_id: Principal, msg: Vec<u8>) -> Reply {
+    let submitted = Instant::now();
+    let submission = canister_methods::submit_ws_message(&agent, &canister_id, msg).await;
+    tokio::spawn(
+        async move {
+            let request_id = submission?;
+            canister_methods::wait_ws_message(&agent, &canister_id, request_id, submitted).await
+        }
+        .in_current_span(),
+    )
+}
+
+struct InFlight {
+    msg: Vec<u8>,
+    reply: Reply,
+    resubmitted: bool,
+}
+
+// The replies are reported in the order of the messages, up to the first failure.
+async fn relay_messages<S, F, R>(
+    mut submit: S,
+    window: usize,
+    report: R,
+    mut queue: mpsc::Receiver<Vec<u8>>,
+) where
+    S: FnMut(Vec<u8>) -> F,
+    F: Future<Output = Reply>,
+    R: Fn(Result<(), CallError>),
+{
+    let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+    let mut queue_open = true;
+    while queue_open || !in_flight.is_empty() {
+        tokio::select! {
+            msg = queue.recv(), if queue_open && in_flight.len() < window => match msg {
+                Some(msg) => {
+                    let reply = submit(msg.clone()).await;
+                    in_flight.push_back(InFlight {
+                        msg,
+                        reply,
+                        resubmitted: false,
+                    });
+                    debug!("{} messages waiting for their replies.", in_flight.len());
+                }
+                None => queue_open = false,
+            },
+            reply = next_reply(&mut in_flight), if !in_flight.is_empty() => {
+                let message = in_flight.pop_front().unwrap();
+                match reply.unwrap_or_else(|e| Err(CallError::Transport(e.to_string()))) {
+                    Ok(()) => report(Ok(())),
+                    // The earlier messages are executed by now.
+                    Err(CallError::Canister(WsError::BadSequence { expected, received }))
+                        if received > expected && !message.resubmitted =>
+                    {
+                        debug!(
+                            "Message {} was executed before message {}, submitting it again.",
+                            received, expected
+                        );
+                        let reply = submit(message.msg.clone()).await;
+                        in_flight.push_front(InFlight {
+                            msg: message.msg,
+                            reply,
+                            resubmitted: true,
+                        });
+                    }
+                    Err(e) => {
+                        for message in in_flight {
+                            message.reply.abort();
+                        }
+                        report(Err(e));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The reply to the oldest message waiting for one.
+async fn next_reply(
+    in_flight: &mut VecDeque<InFlight>,
+) -> Result<Result<(), CallError>, JoinError> {
+    match in_flight.front_mut() {
+        Some(message) => (&mut message.reply).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    // A canister that executes each message after the delay given by its second byte and
+    // expects the sequence numbers in the first byte in order.
+    #[derive(Clone, Default)]
+    struct Canister {
+        next_sequence_num: Arc<Mutex<u64>>,
+        submissions: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Canister {
+        fn submit(&self, msg: Vec<u8>) -> std::future::Ready<Reply> {
+            let canister = self.clone();
+            let received = msg[0] as u64;
+            canister.submissions.lock().unwrap().push(received);
+            std::future::ready(tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(msg[1] as u64)).await;
+                let mut expected = canister.next_sequence_num.lock().unwrap();
+                if received != *expected {
+                    return Err(CallError::Canister(WsError::BadSequence {
+                        expected: *expected,
+                        received,
+                    }));
+                }
+                *expected += 1;
+                Ok(())
+            }))
+        }
+    }
+
+    // Relay the messages and return the reported results.
+    async fn relay(canister: &Canister, messages: Vec<Vec<u8>>) -> Vec<Result<(), CallError>> {
+        let (sender, queue) = mpsc::channel(messages.len());
+        for msg in messages {
+            sender.send(msg).await.unwrap();
+        }
+        drop(sender);
+        let results = Mutex::new(Vec::new());
+        let submit = |msg| canister.submit(msg);
+        let report = |result| results.lock().unwrap().push(result);
+        relay_messages(submit, 4, report, queue).await;
+        results.into_inner().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_executed_in_order() {
+        let canister = Canister::default();
+        let results = relay(&canister, vec![vec![0, 10], vec![1, 20], vec![2, 30]]).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(results.len(), 3);
+        assert_eq!(*canister.submissions.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn message_executed_out_of_order_is_submitted_again() {
+        let canister = Canister::default();
+        // Message 1 is executed before message 0, and message 2 before message 1 is submitted again.
+        let results = relay(&canister, vec![vec![0, 30], vec![1, 10], vec![2, 20]]).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(results.len(), 3);
+        assert_eq!(*canister.next_sequence_num.lock().unwrap(), 3);
+        assert_eq!(*canister.submissions.lock().unwrap(), vec![0, 1, 2, 1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_message_ends_the_relay() {
+        let canister = Canister::default();
+        let results = relay(&canister, vec![vec![0, 10], vec![2, 10], vec![3, 10]]).await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(CallError::Canister(WsError::BadSequence {
+                expected: 1,
+                received: 2
+            }))
+        ));
+        // Message 2 was submitted again once, message 3 not.
+        assert_eq!(*canister.submissions.lock().unwrap(), vec![0, 2, 3, 2]);
+    }
+}
@@ -0,0 +1,100 @@
+use rustls_pemfile::Item;
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{info, warn};
+
+// How often the certificate and key files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Read the certificate chain and the private key from PEM files.
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey, String> {
+    let read_error =
+        |path: &Path, e: std::io::Error| format!("could not read {}: {}", path.display(), e);
+
+    let mut reader = BufReader::new(File::open(cert_file).map_err(|e| read_error(cert_file, e))?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| read_error(cert_file, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", cert_file.display()));
+    }
+
+    let mut reader = BufReader::new(File::open(key_file).map_err(|e| read_error(key_file, e))?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| read_error(key_file, e))? {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                break PrivateKey(key)
+            }
+            Some(_) => continue,
+            None => return Err(format!("no private key in {}", key_file.display())),
+        }
+    };
+    let key = sign::any_supported_type(&key)
+        .map_err(|e| format!("unsupported private key in {}: {}", key_file.display(), e))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+// Serves the current certificate to every handshake, so that it can be replaced while running.
+struct ReloadableCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// Build the TLS acceptor of the websocket server from the certificate and key files, and reload
+// them when either file changes. A failed reload keeps the previous certificate.
+pub fn acceptor(cert_file: PathBuf, key_file: PathBuf) -> Result<TlsAcceptor, String> {
+    let resolver = Arc::new(ReloadableCert {
+        current: RwLock::new(Arc::new(load_certified_key(&cert_file, &key_file)?)),
+    });
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+
+    tokio::spawn(async move {
+        let mut loaded = modified(&cert_file, &key_file);
+        loop {
+            tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+            let current = modified(&cert_file, &key_file);
+            if current == loaded {
+                continue;
+            }
+            loaded = current;
+            match load_certified_key(&cert_file, &key_file) {
+                Ok(key) => {
+                    *resolver.current.write().unwrap() = Arc::new(key);
+                    info!("Reloaded the TLS certificate from {}.", cert_file.display());
+                }
+                Err(e) => warn!("Keeping the previous TLS certificate: {}", e),
+            }
+        }
+    });
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn modified(cert_file: &Path, key_file: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert_file), modified(key_file))
+}
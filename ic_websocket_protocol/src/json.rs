@@ -0,0 +1,114 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{CertMessage, ClientMessage, FirstMessageFromClient};
+
+// JSON encoding of the messages exchanged over the websocket, for clients that send text frames.
+// Binary fields are base64 strings. The signed contents (client_canister_id, val) are the same
+// CBOR bytes as in the binary encoding, so signatures and the canister are not affected.
+
+mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct JsonFirstMessageFromClient {
+    #[serde(with = "base64_bytes")]
+    pub client_canister_id: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    pub sig: Vec<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct JsonClientMessage {
+    #[serde(with = "base64_bytes")]
+    pub val: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    pub sig: Vec<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct JsonCertMessage {
+    pub key: String,
+    #[serde(with = "base64_bytes")]
+    pub val: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    pub cert: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    pub tree: Vec<u8>,
+}
+
+impl From<JsonFirstMessageFromClient> for FirstMessageFromClient {
+    fn from(m: JsonFirstMessageFromClient) -> Self {
+        FirstMessageFromClient {
+            client_canister_id: m.client_canister_id,
+            sig: m.sig,
+        }
+    }
+}
+
+impl From<FirstMessageFromClient> for JsonFirstMessageFromClient {
+    fn from(m: FirstMessageFromClient) -> Self {
+        JsonFirstMessageFromClient {
+            client_canister_id: m.client_canister_id,
+            sig: m.sig,
+        }
+    }
+}
+
+impl From<JsonClientMessage> for ClientMessage {
+    fn from(m: JsonClientMessage) -> Self {
+        ClientMessage {
+            val: m.val,
+            sig: m.sig,
+        }
+    }
+}
+
+impl From<ClientMessage> for JsonClientMessage {
+    fn from(m: ClientMessage) -> Self {
+        JsonClientMessage {
+            val: m.val,
+            sig: m.sig,
+        }
+    }
+}
+
+impl From<JsonCertMessage> for CertMessage {
+    fn from(m: JsonCertMessage) -> Self {
+        CertMessage {
+            key: m.key,
+            val: m.val,
+            cert: m.cert,
+            tree: m.tree,
+        }
+    }
+}
+
+impl From<CertMessage> for JsonCertMessage {
+    fn from(m: CertMessage) -> Self {
+        JsonCertMessage {
+            key: m.key,
+            val: m.val,
+            cert: m.cert,
+            tree: m.tree,
+        }
+    }
+}
+
+pub fn encode_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+pub fn decode_json<T: DeserializeOwned>(text: &str) -> Result<T, serde_json::Error> {
+    serde_json::from_str(text)
+}
@@ -0,0 +1,131 @@
+use candid::CandidType;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_cbor::Serializer;
+
+#[cfg(feature = "json")]
+pub use json::{
+    decode_json, encode_json, JsonCertMessage, JsonClientMessage, JsonFirstMessageFromClient,
+};
+#[cfg(feature = "verify")]
+pub use verify::{
+    verify_cert_message, verify_witness, VerifiedWitness, VerifyError, MAX_CERTIFICATE_AGE,
+};
+
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+#[cfg(feature = "verify")]
+mod verify;
+
+// Wire types shared by the gateway, the canister and the clients.
+
+// Messages have the following required fields (both ways).
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct WebsocketMessage {
+    pub client_id: u64,    // To or from client id.
+    pub sequence_num: u64, // Both ways, messages should arrive with sequence numbers 0, 1, 2...
+    pub timestamp: u64,    // Timestamp of when the message was made for the recipient to inspect.
+    #[serde(with = "serde_bytes")]
+    pub message: Vec<u8>, // Application message encoded in binary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>, // Set for messages published to a topic, with client_id 0 and sequence numbers counted per topic.
+}
+
+// One message in the list returned to the gateway polling for messages.
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct EncodedMessage {
+    pub client_id: u64, // The client that the gateway will forward the message to.
+    pub key: String,    // Key for certificate verification.
+    #[serde(with = "serde_bytes")]
+    pub val: Vec<u8>, // Encoded WebsocketMessage.
+    pub subscribers: Option<Vec<u64>>, // For a published message, the gateway's clients subscribed to the topic.
+}
+
+// List of messages returned to the polling gateway.
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct CertMessages {
+    pub messages: Vec<EncodedMessage>, // List of messages.
+    #[serde(with = "serde_bytes")]
+    pub cert: Vec<u8>, // cert+tree constitute the certificate for all returned messages.
+    #[serde(with = "serde_bytes")]
+    pub tree: Vec<u8>, // cert+tree constitute the certificate for all returned messages.
+    pub remaining: Option<u64>, // Messages queued for the gateway after these, for it to report how far behind it is.
+}
+
+// Message forwarded by the gateway to the client, with the certificate of its val.
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct CertMessage {
+    pub key: String,
+    #[serde(with = "serde_bytes")]
+    pub val: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub cert: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub tree: Vec<u8>,
+}
+
+// Content of the first message, signed by the client and passed on to ws_open().
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct FirstMessage {
+    pub client_id: u64,
+    pub canister_id: String,
+}
+
+// The first message sent by the client over the websocket.
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct FirstMessageFromClient {
+    #[serde(with = "serde_bytes")]
+    pub client_canister_id: Vec<u8>, // Encoded FirstMessage.
+    #[serde(with = "serde_bytes")]
+    pub sig: Vec<u8>,
+}
+
+// Encoded message + signature from client.
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub struct ClientMessage {
+    #[serde(with = "serde_bytes")]
+    pub val: Vec<u8>, // Encoded WebsocketMessage.
+    #[serde(with = "serde_bytes")]
+    pub sig: Vec<u8>,
+}
+
+// Errors returned by the websocket endpoints of the canister.
+#[derive(CandidType, Clone, Deserialize, Serialize, Eq, PartialEq, Debug)]
+pub enum WsError {
+    // An argument could not be decoded.
+    DecodeError(String),
+    // The submitted public key is not a valid ed25519 key.
+    InvalidPublicKey,
+    // No client is registered with this client_id.
+    UnknownClient(u64),
+    // The client's signature does not verify.
+    BadSignature,
+    // The client's message arrived out of order.
+    BadSequence { expected: u64, received: u64 },
+    // The caller is not the gateway registered for the client.
+    Unauthorized,
+}
+
+// Messages are certified under keys that sort in the order of their nonces.
+pub fn message_key(gateway: &str, nonce: u64) -> String {
+    gateway.to_string() + "_" + &format!("{:0>20}", nonce.to_string())
+}
+
+// The nonce of a message, parsed from its key.
+pub fn message_nonce(key: &str) -> Option<u64> {
+    key.rsplit('_').next()?.parse().ok()
+}
+
+// Self-describing CBOR encoding, as used for all messages on the wire.
+pub fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut data = vec![];
+    let mut serializer = Serializer::new(&mut data);
+    serializer.self_describe().unwrap();
+    value.serialize(&mut serializer).unwrap();
+    data
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, serde_cbor::Error> {
+    serde_cbor::from_slice(bytes)
+}
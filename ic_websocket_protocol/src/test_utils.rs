@@ -0,0 +1,111 @@
+use bls12_381::{
+    hash_to_curve::{ExpandMsgXmd, HashToCurve},
+    G1Affine, G1Projective, G2Affine, Scalar,
+};
+use candid::Principal;
+use ic_certification::{
+    hash_tree::{fork, label, leaf, pruned},
+    Certificate, Delegation, HashTree,
+};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{encode, verify::DER_PREFIX, CertMessage};
+
+// Certificates signed the way the IC signs them, for testing the verification of delivered messages.
+// The keys are BLS secret keys given as integers.
+
+// The DER-encoded public key of secret_key, as returned by Agent::read_root_key.
+pub fn der_public_key(secret_key: u64) -> Vec<u8> {
+    let key = G2Affine::from(G2Affine::generator() * Scalar::from(secret_key));
+    [DER_PREFIX.as_slice(), &key.to_compressed()].concat()
+}
+
+// Signs the root hash of the tree the way the subnets of the IC do.
+pub fn certificate(
+    tree: HashTree<'static>,
+    secret_key: u64,
+    delegation: Option<Delegation>,
+) -> Certificate<'static> {
+    let msg = [b"\x0Dic-state-root".as_slice(), &tree.digest()].concat();
+    let point = <G1Projective as HashToCurve<ExpandMsgXmd<sha2_09::Sha256>>>::hash_to_curve(
+        msg,
+        b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_",
+    );
+    let signature = G1Affine::from(point * Scalar::from(secret_key))
+        .to_compressed()
+        .to_vec();
+    Certificate {
+        tree,
+        signature,
+        delegation,
+    }
+}
+
+// The witness for a single message, as returned by the canister.
+pub fn witness(key: &str, val: &[u8]) -> HashTree<'static> {
+    label(
+        "websocket",
+        fork(
+            pruned([3; 32]),
+            label(key.to_string(), leaf(Sha256::digest(val))),
+        ),
+    )
+}
+
+// The state tree of the IC holding the certified data of the canister, issued now.
+pub fn state_tree(canister_id: &Principal, certified_data: [u8; 32]) -> HashTree<'static> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    state_tree_at(canister_id, certified_data, now)
+}
+
+// The state tree of the IC holding the certified data of the canister, issued at time in nanoseconds.
+pub fn state_tree_at(
+    canister_id: &Principal,
+    certified_data: [u8; 32],
+    time: u64,
+) -> HashTree<'static> {
+    fork(
+        label(
+            "canister",
+            label(
+                canister_id.as_slice().to_vec(),
+                label("certified_data", leaf(certified_data)),
+            ),
+        ),
+        label("time", leaf(leb128(time))),
+    )
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+// The message with key and val, certified by the canister with its own witness.
+pub fn cert_message(
+    canister_id: &Principal,
+    key: String,
+    val: Vec<u8>,
+    secret_key: u64,
+) -> CertMessage {
+    let tree = witness(&key, &val);
+    let cert = certificate(state_tree(canister_id, tree.digest()), secret_key, None);
+    CertMessage {
+        key,
+        val,
+        cert: encode(&cert),
+        tree: encode(&tree),
+    }
+}
@@ -0,0 +1,232 @@
+use candid::Principal;
+use ic_certification::{Certificate, HashTree, Label, LookupResult};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{decode, CertMessage};
+
+// Domain separator of the root hash signed by the subnet.
+const IC_STATE_ROOT_DOMAIN_SEPARATOR: &[u8; 14] = b"\x0Dic-state-root";
+// DER prefix of the BLS public keys of the IC, followed by the 96 bytes of the key itself.
+pub(crate) const DER_PREFIX: &[u8; 37] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00";
+const KEY_LENGTH: usize = 96;
+// The canister certifies the messages in a tree labeled with this.
+const LABEL_WEBSOCKET: &[u8] = b"websocket";
+// Certificates issued longer ago are refused, so that old messages cannot be replayed with them.
+// The gateway relays the certificate of a poll right away, this leaves room for clock skew.
+pub const MAX_CERTIFICATE_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub enum VerifyError {
+    // The certificate or the witness tree could not be decoded.
+    Decode(serde_cbor::Error),
+    // The root key or the key of a delegated subnet is not a DER-encoded BLS key.
+    InvalidPublicKey,
+    // The BLS signature of the certificate does not verify.
+    BadSignature,
+    // The subnet that signed the certificate is not responsible for the canister.
+    CanisterNotInRange,
+    // The delegation certificate has a delegation itself, which the IC never issues.
+    NestedDelegation,
+    // The certificate was issued more than MAX_CERTIFICATE_AGE ago.
+    CertificateTooOld,
+    // A value the verification needs is not in the certificate.
+    MissingPath(String),
+    // The witness tree does not match the data certified by the canister.
+    CertifiedDataMismatch,
+    // The message is not in the witness tree under its key.
+    MessageNotCertified,
+    // The message certified under its key has another hash.
+    MessageHashMismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Decode(e) => write!(f, "could not decode certificate: {}", e),
+            VerifyError::InvalidPublicKey => write!(f, "invalid BLS public key"),
+            VerifyError::BadSignature => write!(f, "certificate signature does not verify"),
+            VerifyError::CanisterNotInRange => {
+                write!(f, "certificate is not authorized for the canister")
+            }
+            VerifyError::NestedDelegation => write!(f, "delegation certificate has a delegation"),
+            VerifyError::CertificateTooOld => write!(f, "certificate is too old"),
+            VerifyError::MissingPath(path) => write!(f, "certificate has no value at {}", path),
+            VerifyError::CertifiedDataMismatch => {
+                write!(f, "witness does not match the certified data")
+            }
+            VerifyError::MessageNotCertified => write!(f, "message key is not in the witness"),
+            VerifyError::MessageHashMismatch => {
+                write!(f, "message does not match its certified hash")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<serde_cbor::Error> for VerifyError {
+    fn from(e: serde_cbor::Error) -> Self {
+        VerifyError::Decode(e)
+    }
+}
+
+// Verify that the message was certified by the canister:
+// 1. the certificate is signed by the IC, either with root_key (DER-encoded, as returned by
+//    Agent::read_root_key) or by a subnet the root key delegated the canister to, and was issued
+//    at most MAX_CERTIFICATE_AGE ago,
+// 2. the certified data of the canister is the hash of the witness tree labeled "websocket",
+// 3. the witness holds sha256(val) under the key of the message.
+pub fn verify_cert_message(
+    message: &CertMessage,
+    canister_id: &Principal,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    verify_witness(&message.cert, &message.tree, canister_id, root_key)?
+        .verify_message(&message.key, &message.val)
+}
+
+// A witness tree whose certificate verified, against which the messages it covers are verified.
+// The messages polled together share their certificate and witness, which are verified only once then.
+#[derive(Debug)]
+pub struct VerifiedWitness {
+    witness: HashTree<'static>,
+}
+
+// Verify the steps 1. and 2. of verify_cert_message for the certificate and the witness tree.
+pub fn verify_witness(
+    cert: &[u8],
+    tree: &[u8],
+    canister_id: &Principal,
+    root_key: &[u8],
+) -> Result<VerifiedWitness, VerifyError> {
+    let cert: Certificate = decode(cert)?;
+    verify_certificate(&cert, canister_id, root_key)?;
+    verify_time(&cert)?;
+
+    let witness: HashTree = decode(tree)?;
+    let certified_data = lookup(
+        &cert,
+        [
+            Label::from("canister"),
+            Label::from(canister_id.as_slice()),
+            Label::from("certified_data"),
+        ],
+    )?;
+    if certified_data != witness.digest() {
+        return Err(VerifyError::CertifiedDataMismatch);
+    }
+    Ok(VerifiedWitness { witness })
+}
+
+impl VerifiedWitness {
+    // Verify the step 3. of verify_cert_message for the message with key and val.
+    pub fn verify_message(&self, key: &str, val: &[u8]) -> Result<(), VerifyError> {
+        match self
+            .witness
+            .lookup_path(&[Label::from(LABEL_WEBSOCKET), Label::from(key)])
+        {
+            LookupResult::Found(hash) if hash == Sha256::digest(val).as_slice() => Ok(()),
+            LookupResult::Found(_) => Err(VerifyError::MessageHashMismatch),
+            _ => Err(VerifyError::MessageNotCertified),
+        }
+    }
+}
+
+fn verify_certificate(
+    cert: &Certificate,
+    canister_id: &Principal,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let der_key = match &cert.delegation {
+        None => root_key.to_vec(),
+        Some(delegation) => {
+            // The delegation is itself a certificate signed with the root key, stating the
+            // canister ranges and the key of the subnet.
+            let delegation_cert: Certificate = decode(&delegation.certificate)?;
+            if delegation_cert.delegation.is_some() {
+                return Err(VerifyError::NestedDelegation);
+            }
+            verify_certificate(&delegation_cert, canister_id, root_key)?;
+
+            let subnet_id = Label::from(&delegation.subnet_id);
+            let ranges: Vec<(Principal, Principal)> = decode(lookup(
+                &delegation_cert,
+                [
+                    Label::from("subnet"),
+                    subnet_id.clone(),
+                    Label::from("canister_ranges"),
+                ],
+            )?)?;
+            if !ranges
+                .iter()
+                .any(|(low, high)| low <= canister_id && canister_id <= high)
+            {
+                return Err(VerifyError::CanisterNotInRange);
+            }
+            lookup(
+                &delegation_cert,
+                [Label::from("subnet"), subnet_id, Label::from("public_key")],
+            )?
+            .to_vec()
+        }
+    };
+    let key = extract_der(&der_key)?;
+
+    let mut msg = IC_STATE_ROOT_DOMAIN_SEPARATOR.to_vec();
+    msg.extend_from_slice(&cert.tree.digest());
+    ic_verify_bls_signature::verify_bls_signature(&cert.signature, &msg, key)
+        .map_err(|_| VerifyError::BadSignature)
+}
+
+// The certificate holds the time it was issued at in nanoseconds, LEB128-encoded.
+fn verify_time(cert: &Certificate) -> Result<(), VerifyError> {
+    let time = decode_leb128(lookup(cert, [Label::from("time")])?)
+        .ok_or_else(|| VerifyError::MissingPath("time".to_string()))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    // A certificate from the future only means that the clocks differ.
+    if Duration::from_nanos(now.saturating_sub(time)) > MAX_CERTIFICATE_AGE {
+        return Err(VerifyError::CertificateTooOld);
+    }
+    Ok(())
+}
+
+fn decode_leb128(bytes: &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let bits = u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+        value |= bits;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn extract_der(der_key: &[u8]) -> Result<&[u8], VerifyError> {
+    if der_key.len() != DER_PREFIX.len() + KEY_LENGTH || !der_key.starts_with(DER_PREFIX) {
+        return Err(VerifyError::InvalidPublicKey);
+    }
+    Ok(&der_key[DER_PREFIX.len()..])
+}
+
+fn lookup<'a, const N: usize>(
+    cert: &'a Certificate,
+    path: [Label; N],
+) -> Result<&'a [u8], VerifyError> {
+    match cert.tree.lookup_path(&path) {
+        LookupResult::Found(value) => Ok(value),
+        _ => Err(VerifyError::MissingPath(
+            path.iter()
+                .map(|label| String::from_utf8_lossy(label.as_bytes()).into_owned())
+                .collect::<Vec<_>>()
+                .join("/"),
+        )),
+    }
+}
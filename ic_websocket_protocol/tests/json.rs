@@ -0,0 +1,43 @@
+use ic_websocket_protocol::*;
+
+#[test]
+fn json_messages_use_base64() {
+    let msg = JsonClientMessage {
+        val: vec![0, 1, 2, 255],
+        sig: vec![7; 4],
+    };
+    let text = encode_json(&msg);
+    assert_eq!(text, r#"{"val":"AAEC/w==","sig":"BwcHBw=="}"#);
+    assert_eq!(decode_json::<JsonClientMessage>(&text).unwrap(), msg);
+}
+
+#[test]
+fn json_messages_convert_to_cbor_messages() {
+    let first = FirstMessageFromClient {
+        client_canister_id: encode(&FirstMessage {
+            client_id: 16,
+            canister_id: String::from("bw4dl-smaaa-aaaaa-qaacq-cai"),
+        }),
+        sig: vec![7; 64],
+    };
+    let json: JsonFirstMessageFromClient = decode_json(&encode_json(
+        &JsonFirstMessageFromClient::from(first.clone()),
+    ))
+    .unwrap();
+    assert_eq!(FirstMessageFromClient::from(json), first);
+
+    let cert_message = CertMessage {
+        key: message_key("2vxsx-fae", 16),
+        val: vec![0, 1, 2, 255],
+        cert: vec![1; 100],
+        tree: vec![2; 50],
+    };
+    let json: JsonCertMessage =
+        decode_json(&encode_json(&JsonCertMessage::from(cert_message.clone()))).unwrap();
+    assert_eq!(CertMessage::from(json), cert_message);
+}
+
+#[test]
+fn json_rejects_invalid_base64() {
+    assert!(decode_json::<JsonClientMessage>(r#"{"val":"not base64!","sig":""}"#).is_err());
+}
@@ -0,0 +1,139 @@
+use candid::{Decode, Encode};
+use ic_websocket_protocol::*;
+use serde::Serialize;
+
+fn websocket_message(topic: Option<String>) -> WebsocketMessage {
+    WebsocketMessage {
+        client_id: 16,
+        sequence_num: 3,
+        timestamp: 1_680_000_000_000_000_000,
+        message: vec![0, 1, 2, 255],
+        topic,
+    }
+}
+
+#[test]
+fn websocket_message_cbor_round_trip() {
+    for topic in [None, Some(String::from("news"))] {
+        let msg = websocket_message(topic);
+        let decoded: WebsocketMessage = decode(&encode(&msg)).unwrap();
+        assert_eq!(decoded, msg);
+    }
+}
+
+#[test]
+fn websocket_message_without_topic_field() {
+    // Clients that do not know about topics leave the field out.
+    #[derive(Serialize)]
+    struct LegacyWebsocketMessage {
+        client_id: u64,
+        sequence_num: u64,
+        timestamp: u64,
+        #[serde(with = "serde_bytes")]
+        message: Vec<u8>,
+    }
+
+    let legacy = LegacyWebsocketMessage {
+        client_id: 16,
+        sequence_num: 3,
+        timestamp: 1_680_000_000_000_000_000,
+        message: vec![0, 1, 2, 255],
+    };
+    let decoded: WebsocketMessage = decode(&encode(&legacy)).unwrap();
+    assert_eq!(decoded, websocket_message(None));
+
+    // Messages to a single client are encoded exactly like before.
+    assert_eq!(encode(&websocket_message(None)), encode(&legacy));
+}
+
+#[test]
+fn client_messages_cbor_round_trip() {
+    let first = FirstMessage {
+        client_id: 16,
+        canister_id: String::from("bw4dl-smaaa-aaaaa-qaacq-cai"),
+    };
+    let first_from_client = FirstMessageFromClient {
+        client_canister_id: encode(&first),
+        sig: vec![7; 64],
+    };
+    let decoded: FirstMessageFromClient = decode(&encode(&first_from_client)).unwrap();
+    assert_eq!(decoded, first_from_client);
+    let decoded: FirstMessage = decode(&decoded.client_canister_id).unwrap();
+    assert_eq!(decoded, first);
+
+    let client_message = ClientMessage {
+        val: encode(&websocket_message(None)),
+        sig: vec![7; 64],
+    };
+    let decoded: ClientMessage = decode(&encode(&client_message)).unwrap();
+    assert_eq!(decoded, client_message);
+}
+
+#[test]
+fn cert_message_cbor_round_trip() {
+    let msg = CertMessage {
+        key: message_key("2vxsx-fae", 16),
+        val: encode(&websocket_message(None)),
+        cert: vec![1; 100],
+        tree: vec![2; 50],
+    };
+    let decoded: CertMessage = decode(&encode(&msg)).unwrap();
+    assert_eq!(decoded, msg);
+}
+
+#[test]
+fn cert_messages_candid_round_trip() {
+    let msgs = CertMessages {
+        messages: vec![
+            EncodedMessage {
+                client_id: 16,
+                key: message_key("2vxsx-fae", 16),
+                val: encode(&websocket_message(None)),
+                subscribers: None,
+            },
+            EncodedMessage {
+                client_id: 0,
+                key: message_key("2vxsx-fae", 17),
+                val: encode(&websocket_message(Some(String::from("news")))),
+                subscribers: Some(vec![16, 17]),
+            },
+        ],
+        cert: vec![1; 100],
+        tree: vec![2; 50],
+        remaining: Some(3),
+    };
+    let bytes = Encode!(&msgs).unwrap();
+    assert_eq!(Decode!(&bytes, CertMessages).unwrap(), msgs);
+}
+
+#[test]
+fn ws_error_candid_round_trip() {
+    let results: Vec<Result<(), WsError>> = vec![
+        Ok(()),
+        Err(WsError::DecodeError(String::from("bad cbor"))),
+        Err(WsError::InvalidPublicKey),
+        Err(WsError::UnknownClient(16)),
+        Err(WsError::BadSignature),
+        Err(WsError::BadSequence {
+            expected: 1,
+            received: 2,
+        }),
+        Err(WsError::Unauthorized),
+    ];
+    for result in results {
+        let bytes = Encode!(&result).unwrap();
+        assert_eq!(Decode!(&bytes, Result<(), WsError>).unwrap(), result);
+    }
+}
+
+#[test]
+fn message_keys_sort_by_nonce() {
+    let gateway = "2vxsx-fae";
+    assert_eq!(message_nonce(&message_key(gateway, 0)), Some(0));
+    assert_eq!(
+        message_nonce(&message_key(gateway, u64::MAX)),
+        Some(u64::MAX)
+    );
+    assert!(message_key(gateway, 9) < message_key(gateway, 10));
+    assert_eq!(message_nonce("no-nonce"), None);
+}
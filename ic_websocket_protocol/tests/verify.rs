@@ -0,0 +1,216 @@
+use candid::Principal;
+use ic_certification::{
+    hash_tree::{empty, fork, label, leaf},
+    Delegation, HashTree,
+};
+use ic_websocket_protocol::{test_utils::*, *};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn canister_id() -> Principal {
+    Principal::from_text("bw4dl-smaaa-aaaaa-qaacq-cai").unwrap()
+}
+
+fn cert_message(secret_key: u64) -> CertMessage {
+    let val = encode(&WebsocketMessage {
+        client_id: 16,
+        sequence_num: 0,
+        timestamp: 1_680_000_000_000_000_000,
+        message: vec![0, 1, 2, 255],
+        topic: None,
+    });
+    test_utils::cert_message(
+        &canister_id(),
+        message_key("2vxsx-fae", 16),
+        val,
+        secret_key,
+    )
+}
+
+#[test]
+fn certified_message_verifies() {
+    let root_key = 42;
+    let msg = cert_message(root_key);
+    verify_cert_message(&msg, &canister_id(), &der_public_key(root_key)).unwrap();
+}
+
+#[test]
+fn certificate_signed_with_another_key() {
+    let msg = cert_message(42);
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(43));
+    assert!(matches!(result, Err(VerifyError::BadSignature)));
+}
+
+#[test]
+fn certificate_of_another_canister() {
+    let root_key = 42;
+    let msg = cert_message(root_key);
+    let other = Principal::from_text("2vxsx-fae").unwrap();
+    let result = verify_cert_message(&msg, &other, &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::MissingPath(_))));
+}
+
+#[test]
+fn tampered_message() {
+    let root_key = 42;
+    let mut msg = cert_message(root_key);
+    msg.val.push(0);
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::MessageHashMismatch)));
+
+    let mut msg = cert_message(root_key);
+    msg.key = message_key("2vxsx-fae", 17);
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::MessageNotCertified)));
+}
+
+#[test]
+fn witness_of_other_data() {
+    let root_key = 42;
+    let mut msg = cert_message(root_key);
+    msg.tree = encode(&label("websocket", empty()));
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::CertifiedDataMismatch)));
+}
+
+#[test]
+fn delegated_certificate() {
+    let root_key = 42;
+    let subnet_key = 7;
+    let subnet_id = vec![9; 29];
+
+    let delegation = |low: Principal, high: Principal| {
+        let tree = label(
+            "subnet",
+            label(
+                subnet_id.clone(),
+                fork(
+                    label(
+                        "canister_ranges",
+                        leaf(serde_cbor::to_vec(&vec![(low, high)]).unwrap()),
+                    ),
+                    label("public_key", leaf(der_public_key(subnet_key))),
+                ),
+            ),
+        );
+        Delegation {
+            subnet_id: subnet_id.clone(),
+            certificate: encode(&certificate(tree, root_key, None)),
+        }
+    };
+
+    let mut msg = cert_message(subnet_key);
+    let tree: HashTree = decode(&msg.tree).unwrap();
+    let state = state_tree(&canister_id(), tree.digest());
+    let cert = certificate(
+        state.clone(),
+        subnet_key,
+        Some(delegation(canister_id(), canister_id())),
+    );
+    msg.cert = encode(&cert);
+    verify_cert_message(&msg, &canister_id(), &der_public_key(root_key)).unwrap();
+
+    // The subnet may only certify the canisters in its ranges.
+    let other = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+    let cert = certificate(state, subnet_key, Some(delegation(other, other)));
+    msg.cert = encode(&cert);
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::CanisterNotInRange)));
+}
+
+#[test]
+fn nested_delegation() {
+    let root_key = 42;
+    let subnet_key = 7;
+    let subnet_id = vec![9; 29];
+    let subnet_tree = || {
+        label(
+            "subnet",
+            label(
+                subnet_id.clone(),
+                fork(
+                    label(
+                        "canister_ranges",
+                        leaf(serde_cbor::to_vec(&vec![(canister_id(), canister_id())]).unwrap()),
+                    ),
+                    label("public_key", leaf(der_public_key(subnet_key))),
+                ),
+            ),
+        )
+    };
+    // The subnet delegates further to itself, with a delegation signed by the root key.
+    let delegation = Delegation {
+        subnet_id: subnet_id.clone(),
+        certificate: encode(&certificate(subnet_tree(), root_key, None)),
+    };
+    let nested = Delegation {
+        subnet_id: subnet_id.clone(),
+        certificate: encode(&certificate(subnet_tree(), subnet_key, Some(delegation))),
+    };
+
+    let mut msg = cert_message(subnet_key);
+    let tree: HashTree = decode(&msg.tree).unwrap();
+    let cert = certificate(
+        state_tree(&canister_id(), tree.digest()),
+        subnet_key,
+        Some(nested),
+    );
+    msg.cert = encode(&cert);
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::NestedDelegation)));
+}
+
+#[test]
+fn old_certificate() {
+    let root_key = 42;
+    let mut msg = cert_message(root_key);
+    let tree: HashTree = decode(&msg.tree).unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let certified_at = |age: Duration| {
+        let state = state_tree_at(&canister_id(), tree.digest(), now - age.as_nanos() as u64);
+        encode(&certificate(state, root_key, None))
+    };
+
+    msg.cert = certified_at(MAX_CERTIFICATE_AGE - Duration::from_secs(10));
+    verify_cert_message(&msg, &canister_id(), &der_public_key(root_key)).unwrap();
+
+    msg.cert = certified_at(MAX_CERTIFICATE_AGE + Duration::from_secs(10));
+    let result = verify_cert_message(&msg, &canister_id(), &der_public_key(root_key));
+    assert!(matches!(result, Err(VerifyError::CertificateTooOld)));
+}
+
+#[test]
+fn messages_sharing_a_witness() {
+    let root_key = 42;
+    let keys = [message_key("2vxsx-fae", 16), message_key("2vxsx-fae", 17)];
+    let vals = [vec![1, 2, 3], vec![4, 5, 6]];
+    let tree = label(
+        "websocket",
+        fork(
+            label(keys[0].clone(), leaf(Sha256::digest(&vals[0]))),
+            label(keys[1].clone(), leaf(Sha256::digest(&vals[1]))),
+        ),
+    );
+    let cert = certificate(state_tree(&canister_id(), tree.digest()), root_key, None);
+
+    let witness = verify_witness(
+        &encode(&cert),
+        &encode(&tree),
+        &canister_id(),
+        &der_public_key(root_key),
+    )
+    .unwrap();
+    witness.verify_message(&keys[0], &vals[0]).unwrap();
+    witness.verify_message(&keys[1], &vals[1]).unwrap();
+    assert!(matches!(
+        witness.verify_message(&keys[1], &vals[0]),
+        Err(VerifyError::MessageHashMismatch)
+    ));
+    assert!(matches!(
+        witness.verify_message(&message_key("2vxsx-fae", 18), &vals[0]),
+        Err(VerifyError::MessageNotCertified)
+    ));
+}